@@ -21,10 +21,18 @@ fn main() -> io::Result<()> {
     eprintln!("negotiation complete, created recv queue");
 
     loop {
-        consumer.recv(|buf| {
+        let res = consumer.recv(|buf| {
             check_buf(buf);
             io::Result::Ok(())
-        })?;
+        });
+        match res {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
         // std::thread::sleep(std::time::Duration::from_millis(50));
     }
+    eprintln!("sender closed the queue");
+
+    Ok(())
 }
@@ -33,5 +33,5 @@ fn main() -> io::Result<()> {
         producer.send(|writer| writer.write_all(buf))?;
     }
 
-    Ok(())
+    producer.close()
 }
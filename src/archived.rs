@@ -0,0 +1,163 @@
+use std::io;
+
+use rkyv::{
+    api::high::{HighSerializer, HighValidator},
+    bytecheck::CheckBytes,
+    rancor,
+    ser::{allocator::ArenaHandle, sharing::Share, Positional, Serializer, Writer},
+    util::with_arena,
+    Archive, Archived, Serialize,
+};
+
+use crate::{Control, MemeQueue};
+
+/// Archived messages are placed at this alignment, same as [`rkyv::util::AlignedVec`].
+const ARCHIVE_ALIGN: usize = 16;
+
+/// Serializer used by [`MemeQueue::send_archived()`].
+pub type ArchiveSerializer<'a, 'b> =
+    HighSerializer<ArchiveWriter<'b>, ArenaHandle<'a>, rancor::Error>;
+
+/// [`rkyv`] writer that serializes straight into the queue.
+pub struct ArchiveWriter<'a> {
+    inner: &'a mut dyn io::Write,
+    pos: usize,
+    // `rancor::Error` loses the error kind in release builds, so we keep the original.
+    error: Option<io::Error>,
+}
+
+impl Positional for ArchiveWriter<'_> {
+    fn pos(&self) -> usize {
+        self.pos
+    }
+}
+
+impl<E: rancor::Source> Writer<E> for ArchiveWriter<'_> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), E> {
+        if let Err(err) = self.inner.write_all(bytes) {
+            let res = E::new(io::Error::new(err.kind(), err.to_string()));
+            self.error = Some(err);
+            return Err(res);
+        }
+        self.pos += bytes.len();
+        Ok(())
+    }
+}
+
+/// Returns the size of the archive.
+fn serialize<T>(value: &T, writer: &mut dyn io::Write) -> io::Result<usize>
+where
+    T: for<'a, 'b> Serialize<ArchiveSerializer<'a, 'b>>,
+{
+    let writer = ArchiveWriter {
+        inner: writer,
+        pos: 0,
+        error: None,
+    };
+    with_arena(|arena| {
+        let mut serializer = Serializer::new(writer, arena.acquire(), Share::new());
+        match rkyv::api::serialize_using(value, &mut serializer) {
+            Ok(_root_pos) => Ok(serializer.into_writer().pos),
+            Err(err) => Err(serializer
+                .into_writer()
+                .error
+                .unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, err))),
+        }
+    })
+}
+
+fn access<T>(buf: &[u8]) -> io::Result<&Archived<T>>
+where
+    T: Archive,
+    Archived<T>: for<'a> CheckBytes<HighValidator<'a, rancor::Error>>,
+{
+    rkyv::access::<Archived<T>, rancor::Error>(buf)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+impl<H, C: Control<H>> MemeQueue<H, C> {
+    /// Serializes `value` with [`rkyv`] directly into the queue, aligned so that receiver can
+    /// access it in place with [`MemeQueue::recv_archived()`]. If serialization fails, nothing is
+    /// sent.
+    ///
+    /// Space is reserved with [`MemeQueue::send_reserve_aligned()`], which needs the exact size
+    /// upfront, so `value` is serialized twice: once to measure it, then into the queue.
+    pub fn send_archived<T>(&self, value: &T) -> io::Result<()>
+    where
+        T: for<'a, 'b> Serialize<ArchiveSerializer<'a, 'b>>,
+    {
+        let len = serialize(value, &mut io::sink())?;
+        let mut guard = self.send_reserve_aligned(len, ARCHIVE_ALIGN)?;
+        let written = serialize(value, &mut &mut guard[..])?;
+        if written != len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "value was archived to a different size the second time",
+            ));
+        }
+        guard.commit()
+    }
+
+    /// Receives a message as a validated [`Archived<T>`] in shared memory, without copying or
+    /// allocating.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if the message is not a valid archive. Message
+    /// is consumed either way.
+    ///
+    /// `T` can't be inferred from the callback, so it has to be specified:
+    /// `queue.recv_archived::<Message, _, _, _>(|message| ...)`.
+    pub fn recv_archived<T, R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        T: Archive,
+        Archived<T>: for<'a> CheckBytes<HighValidator<'a, rancor::Error>>,
+        F: FnOnce(&Archived<T>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.recv(|buf| cb(access::<T>(buf)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use rkyv::{Archive, Serialize};
+
+    use crate::testing::{pair, TempPath};
+
+    #[derive(Archive, Serialize)]
+    struct Message {
+        id: u64,
+        name: String,
+        values: Vec<u32>,
+    }
+
+    #[test]
+    fn archived_messages_wrap_around() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        // Many times the ring, with sizes that don't divide it.
+        for id in 0..300_u64 {
+            let message = Message {
+                id,
+                name: format!("message {id}"),
+                values: (0..id as u32 % 40).collect(),
+            };
+            sender.send_archived(&message).unwrap();
+            receiver
+                .recv_archived::<Message, _, _, _>(|archived| {
+                    assert_eq!(archived.id, id);
+                    assert_eq!(archived.name, message.name);
+                    assert_eq!(archived.values.len(), message.values.len());
+                    assert!(archived
+                        .values
+                        .iter()
+                        .zip(&message.values)
+                        .all(|(a, b)| *a == *b));
+                    io::Result::Ok(())
+                })
+                .unwrap();
+        }
+    }
+}
@@ -0,0 +1,572 @@
+//! Broadcast queue: one sender and many receivers, every receiver sees every message.
+//!
+//! Uses the same framing and double-mapped ring as [`MemeQueue`](crate::MemeQueue), but offsets
+//! are monotonic 64-bit positions, so the ring offset is `position % size`. Every receiver has its
+//! own cursor slot in the header page, and the sender can only overwrite data that the slowest
+//! active receiver has already consumed. Receivers can join and leave at any time, a new receiver
+//! only sees messages sent after it joined.
+//!
+//! Multiple receivers require a handshake that allows more than two peers, such as
+//! [`named_file()`](crate::handshake::named_file).
+
+use std::{
+    io, mem,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    check_frame_format, check_offsets_fit,
+    control::{
+        claim_pid, futex_wait, futex_wake, is_alive, liveness_timeout, time_left, Offset,
+        LIVENESS_CHECK_INTERVAL,
+    },
+    handshake::HandshakeResult,
+    mmap::{self, Mmap},
+    preamble::{ControlKind, Preamble},
+    read_frame, write_message, FRAME_HEADER_SIZE,
+};
+
+/// Maximum number of receivers attached to a broadcast queue at the same time.
+pub const MAX_SUBSCRIBERS: usize = 16;
+
+// Aligned to cache line to improve cache hits.
+#[repr(C, align(128))]
+struct Tail {
+    position: AtomicU64,
+    // Lower half of `position`, used as a futex by waiting receivers. It can't wrap around to the
+    // same value while someone waits on it, because the sender is never more than ring size
+    // ahead of any active receiver, and that's less than 4 GiB without `offsets64`.
+    futex: AtomicU32,
+    waiters: AtomicU32,
+    pid: AtomicU32,
+}
+
+#[repr(C, align(128))]
+struct Cursor {
+    position: AtomicU64,
+    // Zero if the slot is free.
+    pid: AtomicU32,
+}
+
+#[repr(C)]
+struct Header {
+    preamble: Preamble,
+    tail: Tail,
+    cursors: [Cursor; MAX_SUBSCRIBERS],
+    // Bumped by receivers when they move their cursors while the sender waits for space.
+    // Lives outside of all cache lines, because it's needed by every side.
+    consumed: AtomicU32,
+    sender_waiters: AtomicU32,
+    frame_format: AtomicU32,
+}
+
+const _: () = assert!(mem::size_of::<Header>() <= 4096);
+
+struct BroadcastRing {
+    header: Mmap,
+    left: Mmap,
+    right: Mmap,
+}
+
+impl BroadcastRing {
+    fn new<H: HandshakeResult>(handshake_result: &H) -> io::Result<Self> {
+        check_offsets_fit(handshake_result.queue_size())?;
+
+        // SAFETY: guaranteed by `HandshakeResult`s contract.
+        let mmap::QueueMmaps {
+            header,
+            left,
+            right,
+        } = unsafe {
+            mmap::QueueMmaps::from_fd(
+                &handshake_result.shmem_fd(),
+                0,
+                handshake_result.queue_size(),
+            )?
+        };
+
+        // If we're the owner, prepare the header page. We don't need any sync, since we're the
+        // owner and the queue is not marked as ready yet.
+        if handshake_result.is_owner() {
+            // SAFETY: we're filling the size of a mapping.
+            unsafe { header.as_ptr().write_bytes(0, header.size()) };
+        }
+
+        let this = Self {
+            header,
+            left,
+            right,
+        };
+        let header = this.header();
+        let queue_size = handshake_result.queue_size();
+        if handshake_result.is_owner() {
+            header.preamble.init(ControlKind::Broadcast, queue_size);
+        } else {
+            header.preamble.check(ControlKind::Broadcast, queue_size)?;
+        }
+        check_frame_format(&header.frame_format, handshake_result.is_owner())?;
+        if handshake_result.is_owner() {
+            header.preamble.publish();
+        }
+        Ok(this)
+    }
+
+    fn header(&self) -> &Header {
+        // SAFETY:
+        // 1. mmaps are page-aligned
+        // 2. all values are valid for atomics
+        // 3. header fits into a page
+        unsafe { &*self.header.as_ptr().cast() }
+    }
+
+    fn size(&self) -> u64 {
+        self.left.size() as u64
+    }
+
+    fn offset(&self, position: u64) -> Offset {
+        (position % self.size()) as Offset
+    }
+
+    /// Wakes the sender if it waits for receivers to free some space.
+    fn notify_sender(&self) {
+        let header = self.header();
+        if header.sender_waiters.load(Ordering::SeqCst) != 0 {
+            header.consumed.fetch_add(1, Ordering::SeqCst);
+            futex_wake(&header.consumed, 1);
+        }
+    }
+}
+
+/// Sending half of a broadcast queue. Only one sender can exist for a queue at a time.
+pub struct BroadcastSender<H> {
+    // Note: field order is important, as it ensures proper drop order.
+    ring: BroadcastRing,
+    handshake_result: H,
+}
+
+impl<H: HandshakeResult> BroadcastSender<H> {
+    pub fn new(handshake_result: H) -> io::Result<Self> {
+        let ring = BroadcastRing::new(&handshake_result)?;
+        if !claim_pid(&ring.header().tail.pid) {
+            return Err(io::Error::new(
+                io::ErrorKind::ResourceBusy,
+                "broadcast queue already has a sender",
+            ));
+        }
+        // Receivers that are already waiting only start checking our liveness once they wake up.
+        let tail = &ring.header().tail;
+        if tail.waiters.load(Ordering::SeqCst) != 0 {
+            futex_wake(&tail.futex, i32::MAX as u32);
+        }
+
+        let mut this = Self {
+            ring,
+            handshake_result,
+        };
+        this.handshake_result.mark_ready()?;
+        Ok(this)
+    }
+}
+
+impl<H> BroadcastSender<H> {
+    /// Sends a message to every receiver, waiting until the slowest one frees enough space.
+    pub fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.send_until(buf, None)
+    }
+
+    /// Like [`BroadcastSender::send()`], but fails with [`io::ErrorKind::TimedOut`] if there's
+    /// still no space after `timeout`.
+    pub fn send_timeout(&mut self, buf: &[u8], timeout: Duration) -> io::Result<()> {
+        self.send_until(buf, Instant::now().checked_add(timeout))
+    }
+
+    /// Like [`BroadcastSender::send()`], but fails with [`io::ErrorKind::TimedOut`] if there's
+    /// still no space by `deadline`.
+    pub fn send_deadline(&mut self, buf: &[u8], deadline: Instant) -> io::Result<()> {
+        self.send_until(buf, Some(deadline))
+    }
+
+    /// Like [`BroadcastSender::send()`], but fails with [`io::ErrorKind::WouldBlock`] instead of
+    /// waiting if the message can't fit right now.
+    pub fn try_send(&mut self, buf: &[u8]) -> io::Result<()> {
+        let frame_size = self.frame_size(buf.len())?;
+        let tail = self.ring.header().tail.position.load(Ordering::Relaxed);
+        // Checking liveness is a syscall per receiver, so only do it if somebody is in the way.
+        if !self.has_space(tail, frame_size, false) && !self.has_space(tail, frame_size, true) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.publish(tail, buf);
+        Ok(())
+    }
+
+    /// Number of currently attached receivers.
+    pub fn subscribers(&self) -> usize {
+        self.ring
+            .header()
+            .cursors
+            .iter()
+            .filter(|cursor| cursor.pid.load(Ordering::Relaxed) != 0)
+            .count()
+    }
+
+    fn send_until(&mut self, buf: &[u8], deadline: Option<Instant>) -> io::Result<()> {
+        let frame_size = self.frame_size(buf.len())?;
+        let header = self.ring.header();
+        // We're the only sender, so nobody else moves the tail.
+        let tail = header.tail.position.load(Ordering::Relaxed);
+
+        while !self.has_space(tail, frame_size, false) {
+            let timeout = match deadline.map(time_left).transpose()? {
+                Some(timeout) => timeout.min(LIVENESS_CHECK_INTERVAL),
+                None => LIVENESS_CHECK_INTERVAL,
+            };
+
+            let consumed = header.consumed.load(Ordering::SeqCst);
+            header.sender_waiters.fetch_add(1, Ordering::SeqCst);
+            // Cursors could've moved since the last check, and receivers could've died.
+            if !self.has_space(tail, frame_size, true) {
+                // Timeout here only means that it's time to check liveness again,
+                // `time_left()` above will report the real one.
+                let _res = futex_wait(&header.consumed, consumed, Some(timeout));
+            }
+            header.sender_waiters.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        self.publish(tail, buf);
+        Ok(())
+    }
+
+    /// Checks whether a frame of `frame_size` fits without overwriting anything that some active
+    /// receiver didn't consume yet. If `evict_dead` is set, frees the slots of dead receivers.
+    fn has_space(&self, tail: u64, frame_size: u64, evict_dead: bool) -> bool {
+        let mut min_position = tail;
+        for cursor in &self.ring.header().cursors {
+            let pid = cursor.pid.load(Ordering::SeqCst);
+            if pid == 0 {
+                continue;
+            }
+            if evict_dead && !is_alive(pid) {
+                // Might fail if somebody already took this slot over, which is fine.
+                let _res = cursor
+                    .pid
+                    .compare_exchange(pid, 0, Ordering::SeqCst, Ordering::Relaxed);
+                continue;
+            }
+            // Slot of a receiver that's just joining could contain a stale position, but it's
+            // always behind the real one, so at worst we'll wait a bit.
+            min_position = min_position.min(cursor.position.load(Ordering::SeqCst));
+        }
+        tail + frame_size - min_position <= self.ring.size()
+    }
+
+    fn publish(&self, tail: u64, buf: &[u8]) {
+        let header = self.ring.header();
+        // SAFETY: `has_space()` checked that nobody reads this part of the ring.
+        unsafe { write_message(&self.ring.left, self.ring.offset(tail), buf) };
+
+        let new_tail = tail + (FRAME_HEADER_SIZE + buf.len()) as u64;
+        header.tail.position.store(new_tail, Ordering::SeqCst);
+        header.tail.futex.store(new_tail as u32, Ordering::SeqCst);
+        if header.tail.waiters.load(Ordering::SeqCst) != 0 {
+            futex_wake(&header.tail.futex, i32::MAX as u32);
+        }
+    }
+
+    fn frame_size(&self, message_size: usize) -> io::Result<u64> {
+        let frame_size = (FRAME_HEADER_SIZE + message_size) as u64;
+        if frame_size > self.ring.size() {
+            return Err(io::Error::other("tried to write too much"));
+        }
+        Ok(frame_size)
+    }
+}
+
+impl<H> Drop for BroadcastSender<H> {
+    fn drop(&mut self) {
+        self.ring.header().tail.pid.store(0, Ordering::Release);
+    }
+}
+
+/// Receiving half of a broadcast queue. Occupies one of [`MAX_SUBSCRIBERS`] cursor slots until
+/// dropped.
+pub struct BroadcastReceiver<H> {
+    // Note: field order is important, as it ensures proper drop order.
+    ring: BroadcastRing,
+    slot: usize,
+    handshake_result: H,
+}
+
+impl<H: HandshakeResult> BroadcastReceiver<H> {
+    pub fn new(handshake_result: H) -> io::Result<Self> {
+        let ring = BroadcastRing::new(&handshake_result)?;
+        let header = ring.header();
+        let slot = header
+            .cursors
+            .iter()
+            .position(|cursor| claim_pid(&cursor.pid))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    "broadcast queue has too many receivers",
+                )
+            })?;
+        // Start from the current tail. The sender might have already moved past it, but it
+        // can't overwrite anything after it without seeing our cursor first.
+        let tail = header.tail.position.load(Ordering::SeqCst);
+        header.cursors[slot].position.store(tail, Ordering::SeqCst);
+        // Stale position of the previous slot owner might've blocked the sender.
+        ring.notify_sender();
+
+        let mut this = Self {
+            ring,
+            slot,
+            handshake_result,
+        };
+        this.handshake_result.mark_ready()?;
+        Ok(this)
+    }
+}
+
+impl<H> BroadcastReceiver<H> {
+    /// Receives the next message. Callback is called with a slice pointing into shared memory.
+    ///
+    /// Fails with [`io::ErrorKind::BrokenPipe`] if the queue is empty and the sender died.
+    pub fn recv<R, E, F>(&mut self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.recv_until(None, cb)
+    }
+
+    /// Like [`BroadcastReceiver::recv()`], but fails with [`io::ErrorKind::TimedOut`] if there's
+    /// no message after `timeout`.
+    pub fn recv_timeout<R, E, F>(&mut self, timeout: Duration, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.recv_until(Instant::now().checked_add(timeout), cb)
+    }
+
+    /// Like [`BroadcastReceiver::recv()`], but fails with [`io::ErrorKind::TimedOut`] if there's
+    /// no message by `deadline`.
+    pub fn recv_deadline<R, E, F>(&mut self, deadline: Instant, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.recv_until(Some(deadline), cb)
+    }
+
+    /// Like [`BroadcastReceiver::recv()`], but fails with [`io::ErrorKind::WouldBlock`] instead
+    /// of waiting if there are no new messages.
+    pub fn try_recv<R, E, F>(&mut self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        match self.recv_now(cb) {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::from(io::ErrorKind::WouldBlock).into()),
+        }
+    }
+
+    fn recv_until<R, E, F>(&mut self, deadline: Option<Instant>, mut cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        loop {
+            match self.recv_now(cb) {
+                Ok(res) => return res,
+                Err((returned_cb, position)) => {
+                    cb = returned_cb;
+                    self.wait(position, deadline)?;
+                }
+            }
+        }
+    }
+
+    /// Receives a message if there's one. Otherwise, gives the callback back along with our
+    /// position to wait on.
+    fn recv_now<R, E, F>(&mut self, cb: F) -> Result<Result<R, E>, (F, u64)>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        let header = self.ring.header();
+        let cursor = &header.cursors[self.slot];
+        // Only we move our cursor.
+        let position = cursor.position.load(Ordering::Relaxed);
+        let tail = header.tail.position.load(Ordering::Acquire);
+        if tail == position {
+            return Err((cb, position));
+        }
+
+        // SAFETY: everything between `position` and `tail` is committed, and the sender can't
+        // overwrite it until we move our cursor.
+        let offset = self.ring.offset(position);
+        let end = (offset as usize + (tail - position) as usize) as Offset;
+        let Some(frame) = (unsafe { read_frame(&self.ring.left, offset, end) }) else {
+            let err = io::Error::new(io::ErrorKind::InvalidData, "corrupted frame header");
+            return Ok(Err(err.into()));
+        };
+        let res = cb(frame.message);
+        // Note: message is consumed even if callback failed.
+        let new_position = position + (frame.end as usize - offset as usize) as u64;
+        cursor.position.store(new_position, Ordering::SeqCst);
+        self.ring.notify_sender();
+        Ok(res)
+    }
+
+    fn wait(&self, position: u64, deadline: Option<Instant>) -> io::Result<()> {
+        let tail = &self.ring.header().tail;
+        let has_sender = tail.pid.load(Ordering::SeqCst) != 0;
+        let (timeout, check_liveness) = liveness_timeout(deadline, has_sender)?;
+
+        tail.waiters.fetch_add(1, Ordering::SeqCst);
+        let res = if tail.position.load(Ordering::SeqCst) == position {
+            futex_wait(&tail.futex, position as u32, timeout)
+        } else {
+            Ok(())
+        };
+        tail.waiters.fetch_sub(1, Ordering::SeqCst);
+
+        match res {
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                let pid = tail.pid.load(Ordering::Acquire);
+                if pid != 0 && !is_alive(pid) {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, "sender is gone"));
+                }
+                // Unless it's the real timeout, it's just a spurious wakeup.
+                if check_liveness {
+                    return Ok(());
+                }
+                Err(err)
+            }
+            res => res,
+        }
+    }
+}
+
+impl<H> Drop for BroadcastReceiver<H> {
+    fn drop(&mut self) {
+        let cursor = &self.ring.header().cursors[self.slot];
+        cursor.pid.store(0, Ordering::SeqCst);
+        // We might've been the slowest receiver.
+        self.ring.notify_sender();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        path::Path,
+        sync::atomic::Ordering,
+        time::{Duration, Instant},
+    };
+
+    use super::{BroadcastReceiver, BroadcastSender, MAX_SUBSCRIBERS};
+    use crate::{
+        handshake::{named_file, NamedFileHandshakeResult},
+        testing::{dead_pid, TempPath},
+    };
+
+    type Sender = BroadcastSender<NamedFileHandshakeResult>;
+    type Receiver = BroadcastReceiver<NamedFileHandshakeResult>;
+
+    /// Sender comes first, so it owns the queue.
+    fn sender(path: &Path) -> Sender {
+        // SAFETY: test files are only used through queues.
+        BroadcastSender::new(unsafe { named_file(path, 4096) }.unwrap()).unwrap()
+    }
+
+    fn receiver(path: &Path) -> io::Result<Receiver> {
+        // SAFETY: test files are only used through queues.
+        BroadcastReceiver::new(unsafe { named_file(path, 4096) }.unwrap())
+    }
+
+    fn recv(receiver: &mut Receiver) -> Vec<u8> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        receiver
+            .recv_deadline(deadline, |buf| io::Result::Ok(buf.to_vec()))
+            .unwrap()
+    }
+
+    #[test]
+    fn every_receiver_sees_every_message() {
+        let path = TempPath::new();
+        let mut sender = sender(&path);
+        let mut receivers = [receiver(&path).unwrap(), receiver(&path).unwrap()];
+        assert_eq!(sender.subscribers(), 2);
+
+        // Many times the ring, so messages wrap around.
+        for idx in 0..500_u32 {
+            let message = idx.to_le_bytes().repeat(idx as usize % 100);
+            sender.send(&message).unwrap();
+            for receiver in &mut receivers {
+                assert_eq!(recv(receiver), message);
+            }
+        }
+    }
+
+    #[test]
+    fn receiver_joins_at_tail() {
+        let path = TempPath::new();
+        let mut sender = sender(&path);
+        sender.send(b"before").unwrap();
+
+        let mut receiver = receiver(&path).unwrap();
+        let err = receiver.try_recv(|_buf| io::Result::Ok(())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        sender.send(b"after").unwrap();
+        assert_eq!(recv(&mut receiver), b"after");
+    }
+
+    #[test]
+    fn gone_receivers_dont_block_sender() {
+        let path = TempPath::new();
+        let mut sender = sender(&path);
+        let stuck = receiver(&path).unwrap();
+        // Joining after the dead one would take its slot over.
+        let dead = receiver(&path).unwrap();
+        dead.ring.header().cursors[dead.slot]
+            .pid
+            .store(dead_pid(), Ordering::SeqCst);
+        assert_eq!(sender.subscribers(), 2);
+
+        sender.send(&[1; 3000]).unwrap();
+        // Receiver that's alive keeps its messages, even if it doesn't receive them.
+        let err = sender
+            .send_timeout(&[2; 3000], Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(sender.subscribers(), 1);
+
+        drop(stuck);
+        assert_eq!(sender.subscribers(), 0);
+        for _ in 0..10 {
+            sender.try_send(&[3; 3000]).unwrap();
+        }
+    }
+
+    #[test]
+    fn subscribers_are_limited() {
+        let path = TempPath::new();
+        let sender = sender(&path);
+        let receivers = (0..MAX_SUBSCRIBERS)
+            .map(|_| receiver(&path).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(sender.subscribers(), MAX_SUBSCRIBERS);
+
+        let Err(err) = receiver(&path) else {
+            panic!("too many receivers joined");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        drop(receivers);
+        receiver(&path).unwrap();
+    }
+}
@@ -1,14 +1,28 @@
+#[cfg(feature = "async")]
+use std::{
+    future::Future,
+    sync::OnceLock,
+    task::{ready, Context, Poll},
+};
 use std::{
     io,
     os::fd::RawFd,
+    ptr,
     sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
 };
 
+#[cfg(feature = "async")]
+use tokio::io::unix::AsyncFd;
+
+#[cfg(feature = "async")]
+use crate::control::{AsyncControl, WaitState, LIVENESS_CHECK_INTERVAL};
 use crate::{
     control::shmem_futex::ShmemFutexGuard,
-    control::Side,
+    control::{liveness_timeout, Offset, Producers, Side},
     handshake::{ExchangeFd, HandshakeResult},
     mmap::Mmap,
+    preamble::ControlKind,
     Control, ShmemFutexControl, ShmemFutexControlConfig,
 };
 
@@ -20,8 +34,11 @@ pub struct EventFdControl {
     shmem_futex: ShmemFutexControl,
     left_event: RawFd,
     right_event: RawFd,
-    last_notify: AtomicU64,
-    clock: quanta::Clock,
+    // Registered lazily, since registration requires a running tokio runtime.
+    #[cfg(feature = "async")]
+    left_async_event: OnceLock<AsyncFd<RawFd>>,
+    #[cfg(feature = "async")]
+    right_async_event: OnceLock<AsyncFd<RawFd>>,
 }
 
 pub struct EventFdGuard<'a>(ShmemFutexGuard<'a>);
@@ -33,6 +50,79 @@ impl EventFdControl {
             Side::Right => self.right_event,
         }
     }
+
+    #[cfg(feature = "async")]
+    fn async_event(&self, side: Side) -> io::Result<&AsyncFd<RawFd>> {
+        let cell = match side {
+            Side::Left => &self.left_async_event,
+            Side::Right => &self.right_async_event,
+        };
+
+        if let Some(async_fd) = cell.get() {
+            return Ok(async_fd);
+        }
+
+        // If we lose the race, our registration is just dropped.
+        let _res = cell.set(AsyncFd::new(self.event(side))?);
+        Ok(cell.get().expect("we just initialized it"))
+    }
+}
+
+impl EventFdControl {
+    /// Waits for a notification on the eventfd of `side`.
+    fn wait_event(&self, side: Side, timeout: Option<Duration>) -> io::Result<()> {
+        let timeout = timeout.map(|timeout| libc::timespec {
+            tv_sec: timeout.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+            tv_nsec: timeout.subsec_nanos().into(),
+        });
+
+        let mut pfd = libc::pollfd {
+            fd: self.event(side),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: we're passing one valid pollfd and a valid timeout.
+        let res = unsafe {
+            libc::ppoll(
+                &mut pfd,
+                1,
+                timeout
+                    .as_ref()
+                    .map_or(ptr::null(), |timeout| timeout as *const libc::timespec),
+                ptr::null(),
+            )
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            // Interrupted wait is just a spurious wakeup.
+            return if err.kind() == io::ErrorKind::Interrupted {
+                Ok(())
+            } else {
+                Err(err)
+            };
+        }
+        if res == 0 {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+
+        match read_event(self.event(side)) {
+            // Somebody else got the notification first, so it's just a spurious wakeup.
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            res => res.map(drop),
+        }
+    }
+}
+
+/// Reads (and thus resets) the eventfd counter. Returns `WouldBlock` if someone else already
+/// consumed the notification.
+fn read_event(fd: RawFd) -> io::Result<u64> {
+    let mut buf = [0_u8; 8];
+    // SAFETY: we're passing a valid length-8 buffer
+    let res = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), 8) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(u64::from_ne_bytes(buf))
 }
 
 impl<H: HandshakeResult + ExchangeFd> Control<H> for EventFdControl {
@@ -47,14 +137,31 @@ impl<H: HandshakeResult + ExchangeFd> Control<H> for EventFdControl {
         Control::<H>::stats(&self.shmem_futex)
     }
 
-    fn new(_config: Self::Config, header: Mmap, handshake_result: &mut H) -> io::Result<Self> {
+    fn new(
+        _config: Self::Config,
+        header: Mmap,
+        queue_size: usize,
+        handshake_result: &mut H,
+    ) -> io::Result<Self> {
+        // Header comes first, so that we don't wait for eventfds from a peer that doesn't use
+        // them.
+        // TODO: translate meaningful config options
+        let shmem_futex = ShmemFutexControl::init(
+            ShmemFutexControlConfig::default(),
+            header,
+            queue_size,
+            ControlKind::EventFd,
+            handshake_result,
+        )?;
+
         let (left_event, right_event) = if handshake_result.is_owner() {
-            let left_event = unsafe { libc::eventfd(0, 0) };
+            // Eventfds are non-blocking, because several waiters may race for one notification.
+            let left_event = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
             if left_event < 0 {
                 return Err(io::Error::last_os_error());
             }
 
-            let right_event = unsafe { libc::eventfd(0, 0) };
+            let right_event = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
             if right_event < 0 {
                 return Err(io::Error::last_os_error());
             }
@@ -67,18 +174,14 @@ impl<H: HandshakeResult + ExchangeFd> Control<H> for EventFdControl {
             (handshake_result.recv_fd()?, handshake_result.recv_fd()?)
         };
 
-        // TODO: translate meaningful config options
-        let shmem_futex =
-            ShmemFutexControl::new(ShmemFutexControlConfig::default(), header, handshake_result)?;
-
-        let clock = quanta::Clock::new();
-        clock.now(); // heat it up
         Ok(Self {
             shmem_futex,
             left_event,
             right_event,
-            last_notify: AtomicU64::new(0),
-            clock,
+            #[cfg(feature = "async")]
+            left_async_event: OnceLock::new(),
+            #[cfg(feature = "async")]
+            right_async_event: OnceLock::new(),
         })
     }
 
@@ -87,16 +190,16 @@ impl<H: HandshakeResult + ExchangeFd> Control<H> for EventFdControl {
     }
 
     #[inline(never)]
-    fn wait(&self, side: Side, expected: u32) -> io::Result<()> {
+    fn wait(&self, side: Side, expected: Offset, deadline: Option<Instant>) -> io::Result<()> {
         let half = self.shmem_futex.half(side);
+        let mut check_liveness = false;
 
-        let before_inc = self.clock.raw();
         self.shmem_futex
             .waiters(side)
             .fetch_add(1, Ordering::SeqCst); // TODO: ordering
-        let after_inc = self.clock.raw();
-        if half.offset.load(Ordering::SeqCst) == expected {
-            let inside_if = self.clock.raw();
+        let res = if half.offset.load(Ordering::SeqCst) == expected {
+            // After registering, so that a peer claiming the side afterwards notifies us.
+            let has_peer = self.shmem_futex.has_peer(side);
             #[cfg(feature = "stats")]
             match side {
                 Side::Left => Control::<H>::stats(self)
@@ -108,70 +211,28 @@ impl<H: HandshakeResult + ExchangeFd> Control<H> for EventFdControl {
             };
 
             crate::debug_output!("waiting for {side:?} to change from {expected:?}");
-
-            let mut pfd = libc::pollfd {
-                fd: self.event(side),
-                events: libc::POLLIN,
-                revents: 0,
-            };
-            let res = unsafe { libc::poll(&mut pfd, 1, 5000 + 1000 * side as i32) };
-            if res < 1 {
-                println!("We waited for {side:?} to change from {expected}, but...");
-                println!("Oh no, we deadlocked (or at least `poll()` from {} returned {res}). That's bad.", self.event(side));
-                println!("revents is {}, btw", pfd.revents);
-                println!(
-                    "We should send the other side 0xDEAD so they can laugh at our common demise."
-                );
-                let res = unsafe {
-                    libc::write(
-                        self.event(side.other()),
-                        &0xDEAD_u64.to_ne_bytes() as *const _ as *const _,
-                        8,
-                    )
-                };
-                println!(
-                    "Nothing will save us. Even write to {}, which resulted in {res}.",
-                    self.event(side.other()),
-                );
-                println!("Timings, for your dark amusement:");
-                dbg!(
-                    before_inc,
-                    after_inc,
-                    inside_if,
-                    self.last_notify.load(Ordering::Relaxed)
-                );
-                println!(
-                    "My last word would be this: {:?}",
-                    self.shmem_futex.header()
-                );
-                panic!("Goodbye, cruel world.");
-            }
-
-            let mut buf = [0_u8; 8];
-            // SAFETY: we're passing a valid length-8 buffer
-            let res = unsafe { libc::read(self.event(side), buf.as_mut_ptr().cast(), 8) };
-            if res < 0 {
-                return Err(io::Error::last_os_error());
-            }
-            if u64::from_ne_bytes(buf) == 0xDEAD {
-                println!("Lmao, the other side deadlocked. What a loser. Surely it's their fault.");
-                println!("We waited for {side:?} to change from {expected}, but guess that'll never happen now.");
-                println!("Well, here's your header, maybe you'll find out why they're such a fuckup: {:?}", self.shmem_futex.header());
-                println!("You could also use some timings ig:");
-                dbg!(
-                    before_inc,
-                    after_inc,
-                    inside_if,
-                    self.last_notify.load(Ordering::Relaxed)
-                );
-                panic!("Welp, nothing we can do about it.");
-            }
-        }
+            liveness_timeout(deadline, has_peer).and_then(|(timeout, check)| {
+                check_liveness = check;
+                self.wait_event(side, timeout)
+            })
+        } else {
+            Ok(())
+        };
         self.shmem_futex
             .waiters(side)
             .fetch_sub(1, Ordering::SeqCst);
 
-        Ok(())
+        match res {
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                self.shmem_futex.check_peer(side)?;
+                // Unless it's the real timeout, it's just a spurious wakeup.
+                if check_liveness {
+                    return Ok(());
+                }
+                Err(err)
+            }
+            res => res,
+        }
     }
 
     #[inline(never)]
@@ -188,7 +249,6 @@ impl<H: HandshakeResult + ExchangeFd> Control<H> for EventFdControl {
                     .right_notify_yields_to_os
                     .fetch_add(1, Ordering::Relaxed),
             };
-            self.last_notify.store(self.clock.raw(), Ordering::Relaxed);
             // SAFETY: we're passing a valid length-8 buffer
             let res =
                 unsafe { libc::write(self.event(side), 1_u64.to_ne_bytes().as_ptr().cast(), 8) };
@@ -200,23 +260,130 @@ impl<H: HandshakeResult + ExchangeFd> Control<H> for EventFdControl {
         Ok(())
     }
 
-    fn load_offset(&self, side: Side) -> u32 {
+    fn load_offset(&self, side: Side) -> Offset {
         Control::<H>::load_offset(&self.shmem_futex, side)
     }
 
-    fn sync_load_offset(&self, side: Side) -> u32 {
+    fn sync_load_offset(&self, side: Side) -> Offset {
         Control::<H>::sync_load_offset(&self.shmem_futex, side)
     }
 
-    fn cached_offset(&self, side: Side) -> Option<u32> {
+    fn cached_offset(&self, side: Side) -> Option<Offset> {
         Control::<H>::cached_offset(&self.shmem_futex, side)
     }
 
-    fn commit_offset(&self, side: Side, offset: u32) {
+    fn commit_offset(&self, side: Side, offset: Offset) {
         Control::<H>::commit_offset(&self.shmem_futex, side, offset)
     }
 
-    fn fix_offsets(&self, left_offset: u32, right_offset: u32) {
+    fn fix_offsets(&self, left_offset: Offset, right_offset: Offset) {
         Control::<H>::fix_offsets(&self.shmem_futex, left_offset, right_offset);
     }
+
+    fn claim(&self, side: Side) -> io::Result<()> {
+        Control::<H>::claim(&self.shmem_futex, side)?;
+        // Waiters don't check liveness of a side that nobody claimed, so they need to know.
+        Control::<H>::notify(self, side)
+    }
+
+    fn release(&self, side: Side) {
+        Control::<H>::release(&self.shmem_futex, side)
+    }
+
+    fn attach_shared(&self, side: Side) -> io::Result<()> {
+        Control::<H>::attach_shared(&self.shmem_futex, side)?;
+        Control::<H>::notify(self, side)
+    }
+
+    fn detach_shared(&self, side: Side) {
+        Control::<H>::detach_shared(&self.shmem_futex, side)
+    }
+
+    fn is_closed(&self) -> bool {
+        Control::<H>::is_closed(&self.shmem_futex)
+    }
+
+    fn set_closed(&self, closed: bool) {
+        Control::<H>::set_closed(&self.shmem_futex, closed)
+    }
+
+    fn epoch(&self) -> u64 {
+        Control::<H>::epoch(&self.shmem_futex)
+    }
+
+    fn producers(&self) -> &Producers {
+        Control::<H>::producers(&self.shmem_futex)
+    }
+
+    fn next_seq(&self) -> &AtomicU64 {
+        Control::<H>::next_seq(&self.shmem_futex)
+    }
+
+    fn expected_seq(&self) -> &AtomicU64 {
+        Control::<H>::expected_seq(&self.shmem_futex)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<H: HandshakeResult + ExchangeFd> AsyncControl<H> for EventFdControl {
+    fn poll_wait(
+        &self,
+        side: Side,
+        expected: Offset,
+        state: &mut WaitState,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !state.registered {
+            self.shmem_futex
+                .waiters(side)
+                .fetch_add(1, Ordering::SeqCst); // TODO: ordering
+            state.registered = true;
+        }
+
+        let half = self.shmem_futex.half(side);
+        let res = loop {
+            if half.offset.load(Ordering::SeqCst) != expected {
+                break Ok(());
+            }
+
+            // If someone claims the side later, they notify us, and we start checking then.
+            if self.shmem_futex.has_peer(side) {
+                let liveness_check = state
+                    .liveness_check
+                    .get_or_insert_with(|| Box::pin(tokio::time::sleep(LIVENESS_CHECK_INTERVAL)));
+                if liveness_check.as_mut().poll(cx).is_ready() {
+                    // Like a timeout of a blocking wait, it's a spurious wakeup unless the peer
+                    // died.
+                    break self.shmem_futex.check_peer(side);
+                }
+            }
+
+            let async_fd = match self.async_event(side) {
+                Ok(async_fd) => async_fd,
+                Err(err) => break Err(err),
+            };
+            let mut guard = match ready!(async_fd.poll_read_ready(cx)) {
+                Ok(guard) => guard,
+                Err(err) => break Err(err),
+            };
+            match guard.try_io(|async_fd| read_event(*async_fd.get_ref())) {
+                Ok(res) => break res.map(drop),
+                // Readiness was cleared, poll again to register the waker.
+                Err(_would_block) => continue,
+            }
+        };
+
+        AsyncControl::<H>::cancel_wait(self, side, state);
+        Poll::Ready(res)
+    }
+
+    fn cancel_wait(&self, side: Side, state: &mut WaitState) {
+        if state.registered {
+            self.shmem_futex
+                .waiters(side)
+                .fetch_sub(1, Ordering::SeqCst);
+            state.registered = false;
+        }
+        state.liveness_check = None;
+    }
 }
@@ -1,13 +1,39 @@
-use std::io;
+use std::{
+    io,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+#[cfg(feature = "async")]
+use std::{
+    marker::PhantomData,
+    pin::Pin,
+    task::{Context, Poll},
+};
 
 use crate::mmap::Mmap;
 
 mod shmem_futex;
+pub(crate) use shmem_futex::{claim_pid, futex_wait, futex_wake, is_alive, our_pid};
 pub use shmem_futex::{ShmemFutexControl, ShmemFutexControlConfig};
 
 mod eventfd;
 pub use eventfd::{EventFdControl, EventFdControlConfig};
 
+/// Position in the queue, see [`Control::load_offset()`].
+///
+/// Offsets go up to twice the queue size, so with 32-bit offsets queues are limited to 2 GiB.
+/// `offsets64` feature switches to 64-bit offsets everywhere, including the header page, so
+/// peers built with and without it can't share a queue.
+#[cfg(not(feature = "offsets64"))]
+pub type Offset = u32;
+#[cfg(feature = "offsets64")]
+pub type Offset = u64;
+
+#[cfg(not(feature = "offsets64"))]
+pub(crate) type AtomicOffset = AtomicU32;
+#[cfg(feature = "offsets64")]
+pub(crate) type AtomicOffset = AtomicU64;
+
 #[derive(Debug, Clone, Copy)]
 pub enum Side {
     Left,
@@ -23,6 +49,136 @@ impl Side {
     }
 }
 
+/// Returns time left until `deadline`, or [`io::ErrorKind::TimedOut`] if it already passed.
+pub(crate) fn time_left(deadline: Instant) -> io::Result<Duration> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Err(io::ErrorKind::TimedOut.into());
+    }
+    Ok(left)
+}
+
+/// How often waiters check whether the processes they're waiting for are still alive.
+pub(crate) const LIVENESS_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Timeout for a single wait until `deadline`. If there's a peer to check, it's short enough for
+/// waiters to check its liveness at least every [`LIVENESS_CHECK_INTERVAL`]. Also returns whether
+/// timing out only means that it's time to check.
+pub(crate) fn liveness_timeout(
+    deadline: Option<Instant>,
+    has_peer: bool,
+) -> io::Result<(Option<Duration>, bool)> {
+    match deadline.map(time_left).transpose()? {
+        Some(timeout) if !has_peer || timeout <= LIVENESS_CHECK_INTERVAL => {
+            Ok((Some(timeout), false))
+        }
+        None if !has_peer => Ok((None, false)),
+        _ => Ok((Some(LIVENESS_CHECK_INTERVAL), true)),
+    }
+}
+
+/// Maximum number of reservations that concurrent producers can write at the same time.
+const MAX_IN_FLIGHT: usize = 64;
+
+/// Reservations of concurrent producers, see [`MemeProducer`](crate::MemeProducer).
+///
+/// Reservations are numbered sequentially and published in order. Everything here except for
+/// `done` is only changed under the right lock.
+#[repr(C, align(128))]
+#[derive(Debug)]
+pub struct Producers {
+    next_seq: AtomicU32,
+    published_seq: AtomicU32,
+    // End offsets of reservations, indexed by sequence number modulo `MAX_IN_FLIGHT`.
+    ends: [AtomicOffset; MAX_IN_FLIGHT],
+    // Pids of producers that made the reservations.
+    pids: [AtomicU32; MAX_IN_FLIGHT],
+    // Non-zero once the reservation with this index is written.
+    done: [AtomicU32; MAX_IN_FLIGHT],
+}
+
+impl Producers {
+    /// Offset where the next reservation starts, or `None` if too many are in flight already.
+    pub(crate) fn head(&self, right_offset: Offset) -> Option<Offset> {
+        let next_seq = self.next_seq.load(Ordering::Relaxed);
+        let in_flight = next_seq.wrapping_sub(self.published_seq.load(Ordering::Relaxed));
+        match in_flight as usize {
+            0 => Some(right_offset),
+            MAX_IN_FLIGHT => None,
+            _ => Some(self.ends[slot(next_seq.wrapping_sub(1))].load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Records a reservation ending at `end` made by process `pid` and returns its sequence
+    /// number.
+    pub(crate) fn push(&self, end: Offset, pid: u32) -> u32 {
+        let seq = self.next_seq.load(Ordering::Relaxed);
+        self.ends[slot(seq)].store(end, Ordering::Relaxed);
+        self.pids[slot(seq)].store(pid, Ordering::Relaxed);
+        self.done[slot(seq)].store(0, Ordering::Relaxed);
+        self.next_seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        seq
+    }
+
+    /// Marks reservation `seq` as written. Doesn't require a lock.
+    pub(crate) fn mark_done(&self, seq: u32) {
+        self.done[slot(seq)].store(1, Ordering::Release);
+    }
+
+    /// First reservation that's not written yet, along with the pid of its producer. Without the
+    /// right lock, it's only a hint.
+    pub(crate) fn first_pending(&self) -> Option<(u32, u32)> {
+        let seq = self.published_seq.load(Ordering::Relaxed);
+        if seq == self.next_seq.load(Ordering::Relaxed)
+            || self.done[slot(seq)].load(Ordering::Acquire) != 0
+        {
+            return None;
+        }
+        Some((seq, self.pids[slot(seq)].load(Ordering::Relaxed)))
+    }
+
+    /// Offset where reservation `seq` ends.
+    pub(crate) fn end(&self, seq: u32) -> Offset {
+        self.ends[slot(seq)].load(Ordering::Relaxed)
+    }
+
+    /// Forgets all written reservations at the start of the queue and returns the end of the
+    /// last one, which is where the right offset should be committed.
+    pub(crate) fn pop_done(&self) -> Option<Offset> {
+        let next_seq = self.next_seq.load(Ordering::Relaxed);
+        let mut seq = self.published_seq.load(Ordering::Relaxed);
+        let mut end = None;
+        while seq != next_seq && self.done[slot(seq)].load(Ordering::Acquire) != 0 {
+            end = Some(self.ends[slot(seq)].load(Ordering::Relaxed));
+            seq = seq.wrapping_add(1);
+        }
+        self.published_seq.store(seq, Ordering::Relaxed);
+        end
+    }
+
+    /// Forgets reservations in flight. They're lost along with the processes that made them.
+    pub(crate) fn reset(&self) {
+        self.next_seq.store(
+            self.published_seq.load(Ordering::Relaxed),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Moves reservations in flight along with offsets, see [`Control::fix_offsets()`].
+    pub(crate) fn shift(&self, by: Offset) {
+        let next_seq = self.next_seq.load(Ordering::Relaxed);
+        let mut seq = self.published_seq.load(Ordering::Relaxed);
+        while seq != next_seq {
+            self.ends[slot(seq)].fetch_sub(by, Ordering::Relaxed);
+            seq = seq.wrapping_add(1);
+        }
+    }
+}
+
+fn slot(seq: u32) -> usize {
+    seq as usize % MAX_IN_FLIGHT
+}
+
 pub trait Control<H>: Sized {
     type Config;
     type LockGuard<'a>
@@ -32,15 +188,125 @@ pub trait Control<H>: Sized {
     #[cfg(feature = "stats")]
     fn stats(&self) -> &crate::stats::Stats;
 
-    fn new(config: Self::Config, header: Mmap, handshake_result: &mut H) -> io::Result<Self>;
+    /// Sets up control of a ring of `queue_size` bytes with its `header` page, or connects to
+    /// the one set up by the owner.
+    fn new(
+        config: Self::Config,
+        header: Mmap,
+        queue_size: usize,
+        handshake_result: &mut H,
+    ) -> io::Result<Self>;
     fn lock(&self, side: Side) -> Self::LockGuard<'_>;
     // TODO: more flexible errors?
-    fn wait(&self, side: Side, expected: u32) -> io::Result<()>;
+    /// Waits until the offset of `side` is probably not `expected` anymore. Spurious wakeups are
+    /// allowed. Fails with [`io::ErrorKind::TimedOut`] if `deadline` passes first, or with
+    /// [`io::ErrorKind::BrokenPipe`] if the process that claimed `side` died.
+    ///
+    /// Only sides claimed with [`Control::claim()`] or shared with [`Control::attach_shared()`]
+    /// are checked for liveness, e.g. by [`MemeSender`](crate::MemeSender) and
+    /// [`MemeWorker`](crate::MemeWorker). A shared side is only gone once all processes sharing
+    /// it are. A plain [`MemeQueue`](crate::MemeQueue) doesn't know which side each peer uses,
+    /// so it claims nothing, and waits on it keep going if the peer dies.
+    fn wait(&self, side: Side, expected: Offset, deadline: Option<Instant>) -> io::Result<()>;
     fn notify(&self, side: Side) -> io::Result<()>;
 
-    fn load_offset(&self, side: Side) -> u32;
-    fn sync_load_offset(&self, side: Side) -> u32;
-    fn cached_offset(&self, side: Side) -> Option<u32>;
-    fn commit_offset(&self, side: Side, offset: u32);
-    fn fix_offsets(&self, left_offset: u32, right_offset: u32);
+    fn load_offset(&self, side: Side) -> Offset;
+    fn sync_load_offset(&self, side: Side) -> Offset;
+    fn cached_offset(&self, side: Side) -> Option<Offset>;
+    fn commit_offset(&self, side: Side, offset: Offset);
+    fn fix_offsets(&self, left_offset: Offset, right_offset: Offset);
+
+    /// Records that this process exclusively owns the side (receiver owns left, sender owns
+    /// right). Fails if a live process already owns it.
+    fn claim(&self, side: Side) -> io::Result<()>;
+    fn release(&self, side: Side);
+    /// Records one more process sharing the side (workers share left, producers share right).
+    /// Fails if the side is claimed exclusively, or if too many processes share it already.
+    fn attach_shared(&self, side: Side) -> io::Result<()>;
+    fn detach_shared(&self, side: Side);
+    /// Whether the sender closed the queue, see [`MemeSender::close()`](crate::MemeSender::close).
+    fn is_closed(&self) -> bool;
+    fn set_closed(&self, closed: bool);
+    /// Number of times the queue was recovered after a crash, see
+    /// [`ShmemFutexControlConfig::recover`].
+    fn epoch(&self) -> u64;
+    fn producers(&self) -> &Producers;
+    /// Sequence number of the next message sent with [`MessageMeta`](crate::MessageMeta). Only
+    /// changed under the right lock.
+    fn next_seq(&self) -> &AtomicU64;
+    /// Sequence number of the next message with [`MessageMeta`](crate::MessageMeta) that
+    /// receivers expect. Only changed under the left lock.
+    fn expected_seq(&self) -> &AtomicU64;
+}
+
+/// A [`Control`] that can park a task instead of a thread.
+#[cfg(feature = "async")]
+pub trait AsyncControl<H>: Control<H> {
+    /// Async version of [`Control::wait()`], without a deadline. While the side is claimed or
+    /// shared, wakes up every 100ms to check liveness, so the tokio runtime must have timers
+    /// enabled.
+    ///
+    /// `state` must be passed to [`AsyncControl::cancel_wait()`] if the wait is abandoned before
+    /// completion, or the other side will keep notifying us forever.
+    fn poll_wait(
+        &self,
+        side: Side,
+        expected: Offset,
+        state: &mut WaitState,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>>;
+    fn cancel_wait(&self, side: Side, state: &mut WaitState);
+}
+
+/// State of an async wait, see [`AsyncControl::poll_wait()`].
+#[cfg(feature = "async")]
+#[derive(Debug, Default)]
+pub struct WaitState {
+    /// Whether we're counted as a waiter on this side.
+    pub(crate) registered: bool,
+    /// When to check liveness next, set while we're registered.
+    pub(crate) liveness_check: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+#[cfg(feature = "async")]
+impl WaitState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Cancels the wait on drop, so async waits can be safely cancelled.
+#[cfg(feature = "async")]
+pub(crate) struct AsyncWaiter<'a, H, C: AsyncControl<H>> {
+    control: &'a C,
+    side: Side,
+    state: WaitState,
+    _handshake_result: PhantomData<fn() -> H>,
+}
+
+#[cfg(feature = "async")]
+impl<'a, H, C: AsyncControl<H>> AsyncWaiter<'a, H, C> {
+    pub(crate) fn new(control: &'a C, side: Side) -> Self {
+        Self {
+            control,
+            side,
+            state: WaitState::new(),
+            _handshake_result: PhantomData,
+        }
+    }
+
+    pub(crate) async fn wait(&mut self, expected: Offset) -> io::Result<()> {
+        std::future::poll_fn(|cx| {
+            self.control
+                .poll_wait(self.side, expected, &mut self.state, cx)
+        })
+        .await
+    }
+}
+
+#[cfg(feature = "async")]
+impl<H, C: AsyncControl<H>> Drop for AsyncWaiter<'_, H, C> {
+    fn drop(&mut self) {
+        self.control.cancel_wait(self.side, &mut self.state);
+    }
 }
@@ -1,37 +1,112 @@
 use std::{
-    io, ptr,
-    sync::atomic::{AtomicU32, Ordering},
+    io, mem, ptr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Once,
+    },
+    time::{Duration, Instant},
 };
 
 use crate::{
-    control::{Control, Side},
+    check_frame_format,
+    control::{
+        liveness_timeout, AtomicOffset, Control, Offset, Producers, Side, LIVENESS_CHECK_INTERVAL,
+    },
     handshake::HandshakeResult,
     mmap::Mmap,
+    preamble::{ControlKind, Preamble},
+    FRAME_FORMAT,
 };
 
 // Aligned to cache line to improve cache hits.
 #[repr(C, align(128))]
 #[derive(Debug)]
 pub(crate) struct Half {
-    pub(crate) offset: AtomicU32,
+    pub(crate) offset: AtomicOffset,
+    // Pid of the process holding the lock, with `CONTENDED` set if someone might be waiting for
+    // it, or zero if it's free.
     lock: AtomicU32,
-    cached_other_offset: AtomicU32,
+    cached_other_offset: AtomicOffset,
+    // Lower half of `offset` for waiters to use as a futex. Offsets never change by a multiple of
+    // 4 GiB between a commit and a wait, so it's always different when the offset is.
+    #[cfg(feature = "offsets64")]
+    futex: AtomicU32,
+}
+
+impl Half {
+    /// The word that waiters sleep on with [`futex_wait()`] until offset changes.
+    fn futex(&self) -> &AtomicU32 {
+        #[cfg(not(feature = "offsets64"))]
+        return &self.offset;
+        #[cfg(feature = "offsets64")]
+        return &self.futex;
+    }
+
+    fn store_offset(&self, offset: Offset, ordering: Ordering) {
+        self.offset.store(offset, ordering);
+        #[cfg(feature = "offsets64")]
+        self.futex.store(offset as u32, ordering);
+    }
 }
 
 #[repr(C)]
 #[derive(Debug)]
 pub(crate) struct Header {
+    preamble: Preamble,
+    // Number of times the queue was recovered.
+    epoch: AtomicU64,
     left: Half,
     right: Half,
     // Waiters live outside of both cache lines, because they're commonly
     // needed by both sides.
     left_waiters: AtomicU32,
     right_waiters: AtomicU32,
+    // Pids of the receiver (left) and the sender (right), or zero if the side is not claimed.
+    // Sides can also be shared by workers or producers, see `SHARED`.
+    left_pid: AtomicU32,
+    right_pid: AtomicU32,
+    // Comes before anything that depends on `offsets64`, so it's at the same place regardless.
+    frame_format: AtomicU32,
+    // Non-zero once the sender closed the queue.
+    closed: AtomicU32,
+    next_seq: AtomicU64,
+    expected_seq: AtomicU64,
+    producers: Producers,
+    // Pids of processes sharing the left (workers) and the right (producers) side, one per
+    // attachment, or zero for free slots.
+    left_shared: [AtomicU32; MAX_SHARED],
+    right_shared: [AtomicU32; MAX_SHARED],
 }
 
+const _: () = assert!(mem::size_of::<Header>() <= 4096);
+
+/// Side pid of a side that's shared by workers (left) or producers (right), whose pids are in
+/// `left_shared` and `right_shared`. Real pids never have this bit set, since they're limited to
+/// 2^22 on Linux.
+const SHARED: u32 = 1 << 31;
+
+/// Set in a lock if someone might be waiting for it. Real pids never have it set, see `SHARED`.
+const CONTENDED: u32 = 1 << 31;
+
+/// Maximum number of attachments to a shared side. Every worker and producer is one, even if
+/// they're in the same process.
+const MAX_SHARED: usize = 64;
+
 #[derive(Debug, Default, Clone)]
 pub struct ShmemFutexControlConfig {
     pub spin_on_wait: usize,
+    /// If we're the owner and the queue was left behind by a crashed process, resume from the
+    /// committed offsets instead of starting from scratch. Messages that weren't committed yet are
+    /// lost, and ones that weren't consumed yet are delivered again.
+    ///
+    /// Queue must be opened with the same size it was created with (see
+    /// [`named_file()`](crate::handshake::named_file)), otherwise it's reset anyway. So is a
+    /// queue whose committed offsets don't fit its size, e.g. because the header got corrupted.
+    ///
+    /// Every process that used the queue before must have exited. Processes that are still
+    /// attached don't need a recovery: if a peer dies while holding a lock, they take it over
+    /// once they notice, and only lose what the peer didn't commit.
+    pub recover: bool,
 }
 
 pub struct ShmemFutexControl {
@@ -45,7 +120,7 @@ impl ShmemFutexControl {
     pub(crate) fn header(&self) -> &Header {
         // SAFETY:
         // 1. mmaps are page-aligned
-        // 2. all values are valid for u32
+        // 2. all values are valid for atomic integers
         unsafe { &*self.header.as_ptr().cast() }
     }
 
@@ -64,65 +139,225 @@ impl ShmemFutexControl {
             Side::Right => &header.right_waiters,
         }
     }
-}
-
-pub struct ShmemFutexGuard<'a> {
-    futex: &'a AtomicU32,
-}
 
-impl<H: HandshakeResult> Control<H> for ShmemFutexControl {
-    type Config = ShmemFutexControlConfig;
-    type LockGuard<'a> = ShmemFutexGuard<'a>
-    where
-        Self: 'a;
-
-    #[cfg(feature = "stats")]
-    fn stats(&self) -> &crate::stats::Stats {
-        &self.stats
+    pub(crate) fn pid(&self, side: Side) -> &AtomicU32 {
+        let header = self.header();
+        match side {
+            Side::Left => &header.left_pid,
+            Side::Right => &header.right_pid,
+        }
     }
 
-    fn new(config: Self::Config, header: Mmap, handshake_result: &mut H) -> io::Result<Self> {
-        // If we're the owner, prepare the header page. We don't need any sync, since we're the
-        // owner and the queue is not marked as ready yet.
-        if handshake_result.is_owner() {
-            // SAFETY: we're filling the size of a mapping.
-            unsafe { header.as_ptr().write_bytes(0, header.size()) };
+    fn shared(&self, side: Side) -> &[AtomicU32; MAX_SHARED] {
+        let header = self.header();
+        match side {
+            Side::Left => &header.left_shared,
+            Side::Right => &header.right_shared,
         }
+    }
+
+    /// Whether any process sharing `side` is still alive.
+    fn is_shared_alive(&self, side: Side) -> bool {
+        self.shared(side).iter().any(|pid| {
+            let pid = pid.load(Ordering::SeqCst);
+            pid != 0 && is_alive(pid)
+        })
+    }
 
+    /// Sets up the header page of a ring of `queue_size` bytes, or checks that the owner set it
+    /// up for the same `kind` of control. Also used by [`EventFdControl`](crate::EventFdControl),
+    /// which shares the header layout.
+    pub(crate) fn init<H: HandshakeResult>(
+        config: ShmemFutexControlConfig,
+        header: Mmap,
+        queue_size: usize,
+        kind: ControlKind,
+        handshake_result: &H,
+    ) -> io::Result<Self> {
+        let is_owner = handshake_result.is_owner();
         let this = Self {
             header,
             config,
             #[cfg(feature = "stats")]
             stats: crate::stats::Stats::default(),
         };
+
+        // If we're the owner, prepare the header page. We don't need any sync, since we're the
+        // owner and the queue is not marked as ready yet.
+        if is_owner {
+            if this.config.recover && this.is_valid(kind, queue_size) {
+                this.recover();
+            } else {
+                // SAFETY: we're filling the size of a mapping.
+                unsafe { this.header.as_ptr().write_bytes(0, this.header.size()) };
+                this.header().preamble.init(kind, queue_size);
+            }
+        } else {
+            this.header().preamble.check(kind, queue_size)?;
+        }
+
         let header = this.header();
         header
             .left
             .cached_other_offset
-            .store(u32::MAX, Ordering::Relaxed);
+            .store(Offset::MAX, Ordering::Relaxed);
         header
             .right
             .cached_other_offset
-            .store(u32::MAX, Ordering::Relaxed);
+            .store(Offset::MAX, Ordering::Relaxed);
+        check_frame_format(&header.frame_format, is_owner)?;
+        if is_owner {
+            header.preamble.publish();
+        }
         Ok(this)
     }
 
+    /// Whether the header was set up by a compatible process for a ring of `queue_size` bytes,
+    /// and its committed offsets make sense for it.
+    fn is_valid(&self, kind: ControlKind, queue_size: usize) -> bool {
+        let header = self.header();
+        if header.preamble.check(kind, queue_size).is_err()
+            || header.frame_format.load(Ordering::Relaxed) != FRAME_FORMAT
+        {
+            return false;
+        }
+        // Offsets are moved back by the ring size before right one gets past twice of it.
+        let left_offset = header.left.offset.load(Ordering::Relaxed) as usize;
+        let right_offset = header.right.offset.load(Ordering::Relaxed) as usize;
+        left_offset <= right_offset
+            && right_offset <= 2 * queue_size
+            && right_offset - left_offset <= queue_size
+    }
+
+    /// Forgets everything that belonged to the processes that used the queue before: locks,
+    /// waiters, claimed sides and producer reservations. Committed offsets and sequence numbers
+    /// are kept. We must be the only process with access to the queue, otherwise we could reset
+    /// a lock that a live process holds.
+    fn recover(&self) {
+        let header = self.header();
+        for half in [&header.left, &header.right] {
+            half.lock.store(0, Ordering::Relaxed);
+            // Process might have died between updating the offset and its futex.
+            half.store_offset(half.offset.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        for counter in [
+            &header.left_waiters,
+            &header.right_waiters,
+            &header.left_pid,
+            &header.right_pid,
+        ]
+        .into_iter()
+        .chain(&header.left_shared)
+        .chain(&header.right_shared)
+        {
+            counter.store(0, Ordering::Relaxed);
+        }
+        header.producers.reset();
+        // Whoever closed the queue is gone too, new peers start with an open one.
+        header.closed.store(0, Ordering::Relaxed);
+        header.epoch.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Whether `side` is claimed or shared, i.e. whether there's anyone for
+    /// [`ShmemFutexControl::check_peer()`] to check.
+    pub(crate) fn has_peer(&self, side: Side) -> bool {
+        self.pid(side).load(Ordering::SeqCst) != 0
+    }
+
+    /// Wakes everyone waiting on `side`, so that they start checking liveness of whoever just
+    /// claimed or attached to it.
+    fn wake_waiters(&self, side: Side) {
+        if self.waiters(side).load(Ordering::SeqCst) != 0 {
+            futex_wake(self.half(side).futex(), i32::MAX as u32);
+        }
+    }
+
+    /// Fails with [`io::ErrorKind::BrokenPipe`] if the process that claimed `side` died, or if
+    /// every process sharing it did.
+    pub(crate) fn check_peer(&self, side: Side) -> io::Result<()> {
+        let alive = match self.pid(side).load(Ordering::Acquire) {
+            0 => true,
+            SHARED => self.is_shared_alive(side),
+            pid => is_alive(pid),
+        };
+        if alive {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            match side {
+                Side::Left => "receiver is gone",
+                Side::Right => "sender is gone",
+            },
+        ))
+    }
+}
+
+pub struct ShmemFutexGuard<'a> {
+    futex: &'a AtomicU32,
+}
+
+impl<H: HandshakeResult> Control<H> for ShmemFutexControl {
+    type Config = ShmemFutexControlConfig;
+    type LockGuard<'a> = ShmemFutexGuard<'a>
+    where
+        Self: 'a;
+
+    #[cfg(feature = "stats")]
+    fn stats(&self) -> &crate::stats::Stats {
+        &self.stats
+    }
+
+    fn new(
+        config: Self::Config,
+        header: Mmap,
+        queue_size: usize,
+        handshake_result: &mut H,
+    ) -> io::Result<Self> {
+        Self::init(
+            config,
+            header,
+            queue_size,
+            ControlKind::ShmemFutex,
+            handshake_result,
+        )
+    }
+
     fn lock(&self, side: Side) -> Self::LockGuard<'_> {
         let futex = &self.half(side).lock;
+        let our_pid = our_pid();
 
-        if futex
-            .compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed)
-            .is_err()
-        {
-            while futex.swap(2, Ordering::Acquire) != 0 {
-                futex_wait(futex, 2);
+        let Err(mut holder) =
+            futex.compare_exchange(0, our_pid, Ordering::Acquire, Ordering::Relaxed)
+        else {
+            return ShmemFutexGuard { futex };
+        };
+        loop {
+            // Someone else might be waiting too, so we keep the lock marked as contended.
+            let (new, acquired) = match holder {
+                0 => (our_pid | CONTENDED, true),
+                _ if holder & CONTENDED == 0 => (holder | CONTENDED, false),
+                _ => {
+                    // Times out now and then to check whether the holder is still alive.
+                    let res = futex_wait(futex, holder, Some(LIVENESS_CHECK_INTERVAL));
+                    if res.is_err() && !is_alive(holder & !CONTENDED) {
+                        // Holder died without releasing the lock, so we can take it over.
+                        (our_pid | CONTENDED, true)
+                    } else {
+                        holder = futex.load(Ordering::Relaxed);
+                        continue;
+                    }
+                }
+            };
+            match futex.compare_exchange(holder, new, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) if acquired => return ShmemFutexGuard { futex },
+                Ok(_) => holder = new,
+                Err(other) => holder = other,
             }
         }
-
-        ShmemFutexGuard { futex }
     }
 
-    fn wait(&self, side: Side, expected: u32) -> io::Result<()> {
+    fn wait(&self, side: Side, expected: Offset, deadline: Option<Instant>) -> io::Result<()> {
         let half = self.half(side);
 
         // TODO: maybe exponential backoff spinning?
@@ -133,6 +368,7 @@ impl<H: HandshakeResult> Control<H> for ShmemFutexControl {
             std::hint::spin_loop();
         }
 
+        let (timeout, check_liveness) = liveness_timeout(deadline, self.has_peer(side))?;
         let waiters = self.waiters(side);
 
         waiters.fetch_add(1, Ordering::AcqRel); // TODO: ordering
@@ -147,10 +383,21 @@ impl<H: HandshakeResult> Control<H> for ShmemFutexControl {
                 .right_wait_yields_to_os
                 .fetch_add(1, Ordering::Relaxed),
         };
-        futex_wait(&half.offset, expected);
+        #[allow(clippy::unnecessary_cast)] // `Offset` is `u32` without `offsets64`.
+        let res = futex_wait(half.futex(), expected as u32, timeout);
         waiters.fetch_sub(1, Ordering::Release);
 
-        Ok(())
+        match res {
+            Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                self.check_peer(side)?;
+                // Unless it's the real timeout, it's just a spurious wakeup.
+                if check_liveness {
+                    return Ok(());
+                }
+                Err(err)
+            }
+            res => res,
+        }
     }
 
     fn notify(&self, side: Side) -> io::Result<()> {
@@ -168,17 +415,17 @@ impl<H: HandshakeResult> Control<H> for ShmemFutexControl {
                     .right_notify_yields_to_os
                     .fetch_add(1, Ordering::Relaxed),
             };
-            futex_wake(&half.offset, 1);
+            futex_wake(half.futex(), 1);
         }
 
         Ok(())
     }
 
-    fn load_offset(&self, side: Side) -> u32 {
+    fn load_offset(&self, side: Side) -> Offset {
         self.half(side).offset.load(Ordering::Relaxed)
     }
 
-    fn sync_load_offset(&self, side: Side) -> u32 {
+    fn sync_load_offset(&self, side: Side) -> Offset {
         let res = self.half(side).offset.load(Ordering::Acquire);
         self.half(side.other())
             .cached_other_offset
@@ -186,23 +433,23 @@ impl<H: HandshakeResult> Control<H> for ShmemFutexControl {
         res
     }
 
-    fn cached_offset(&self, side: Side) -> Option<u32> {
+    fn cached_offset(&self, side: Side) -> Option<Offset> {
         let cached = self
             .half(side.other())
             .cached_other_offset
             .load(Ordering::Relaxed);
 
-        (cached != u32::MAX).then_some(cached)
+        (cached != Offset::MAX).then_some(cached)
     }
 
-    fn commit_offset(&self, side: Side, offset: u32) {
-        self.half(side).offset.store(offset, Ordering::Release)
+    fn commit_offset(&self, side: Side, offset: Offset) {
+        self.half(side).store_offset(offset, Ordering::Release)
     }
 
-    fn fix_offsets(&self, left_offset: u32, right_offset: u32) {
+    fn fix_offsets(&self, left_offset: Offset, right_offset: Offset) {
         let header = self.header();
-        header.left.offset.store(left_offset, Ordering::Relaxed);
-        header.right.offset.store(right_offset, Ordering::Relaxed);
+        header.left.store_offset(left_offset, Ordering::Relaxed);
+        header.right.store_offset(right_offset, Ordering::Relaxed);
         header
             .left
             .cached_other_offset
@@ -212,32 +459,416 @@ impl<H: HandshakeResult> Control<H> for ShmemFutexControl {
             .cached_other_offset
             .store(left_offset, Ordering::Relaxed);
     }
+
+    fn claim(&self, side: Side) -> io::Result<()> {
+        let our_pid = our_pid();
+        let pid = self.pid(side);
+        let mut current = 0;
+        loop {
+            match pid.compare_exchange(current, our_pid, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => {
+                    self.wake_waiters(side);
+                    return Ok(());
+                }
+                // Everyone sharing the side died without detaching, so we can take it over.
+                Err(SHARED) if !self.is_shared_alive(side) => current = SHARED,
+                Err(SHARED) => break,
+                // Previous owner died without releasing the side.
+                Err(other_pid) if other_pid == 0 || !is_alive(other_pid) => current = other_pid,
+                Err(_) => break,
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::ResourceBusy,
+            match side {
+                Side::Left => "queue already has a receiver",
+                Side::Right => "queue already has a sender",
+            },
+        ))
+    }
+
+    fn release(&self, side: Side) {
+        self.pid(side).store(0, Ordering::Release);
+    }
+
+    fn attach_shared(&self, side: Side) -> io::Result<()> {
+        let pid = self.pid(side);
+        let mut slot: Option<&AtomicU32> = None;
+        loop {
+            let current = pid.load(Ordering::SeqCst);
+            if current != SHARED {
+                // Exclusive owner might have died, then we can take the side over.
+                if current != 0 && is_alive(current) {
+                    if let Some(slot) = slot {
+                        slot.store(0, Ordering::SeqCst);
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::ResourceBusy,
+                        match side {
+                            Side::Left => "queue already has an exclusive receiver",
+                            Side::Right => "queue already has an exclusive sender",
+                        },
+                    ));
+                }
+                if pid
+                    .compare_exchange(current, SHARED, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_err()
+                {
+                    continue;
+                }
+            }
+
+            if slot.is_none() {
+                // Slots of dead processes are free too.
+                slot = self.shared(side).iter().find(|slot| claim_pid(slot));
+                if slot.is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::ResourceBusy,
+                        match side {
+                            Side::Left => "queue has too many workers",
+                            Side::Right => "queue has too many producers",
+                        },
+                    ));
+                }
+            }
+            // Meanwhile, the last one to detach might have left the side unclaimed, and then
+            // someone else might have claimed it.
+            if pid.load(Ordering::SeqCst) == SHARED {
+                self.wake_waiters(side);
+                return Ok(());
+            }
+        }
+    }
+
+    fn detach_shared(&self, side: Side) {
+        let our_pid = our_pid();
+        let shared = self.shared(side);
+        let _detached = shared.iter().any(|slot| {
+            slot.compare_exchange(our_pid, 0, Ordering::SeqCst, Ordering::Relaxed)
+                .is_ok()
+        });
+        // Last one leaves the side unclaimed.
+        if shared.iter().all(|slot| slot.load(Ordering::SeqCst) == 0) {
+            let pid = self.pid(side);
+            let _res = pid.compare_exchange(SHARED, 0, Ordering::SeqCst, Ordering::Relaxed);
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.header().closed.load(Ordering::Acquire) != 0
+    }
+
+    fn set_closed(&self, closed: bool) {
+        self.header().closed.store(closed.into(), Ordering::Release);
+    }
+
+    fn epoch(&self) -> u64 {
+        self.header().epoch.load(Ordering::Relaxed)
+    }
+
+    fn producers(&self) -> &Producers {
+        &self.header().producers
+    }
+
+    fn next_seq(&self) -> &AtomicU64 {
+        &self.header().next_seq
+    }
+
+    fn expected_seq(&self) -> &AtomicU64 {
+        &self.header().expected_seq
+    }
+}
+
+/// Stores our pid into `pid` if it's zero or belongs to a dead process.
+/// Returns `false` if it's held by another live process.
+pub(crate) fn claim_pid(pid: &AtomicU32) -> bool {
+    let our_pid = our_pid();
+    let mut current = 0;
+    loop {
+        match pid.compare_exchange(current, our_pid, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => return true,
+            // Previous owner died without releasing the pid, so we can take it over.
+            Err(other_pid) if other_pid == 0 || !is_alive(other_pid) => current = other_pid,
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Our pid, cached since locking needs it and `getpid()` is a syscall. Forked children forget it.
+pub(crate) fn our_pid() -> u32 {
+    static PID: AtomicU32 = AtomicU32::new(0);
+    static AT_FORK: Once = Once::new();
+
+    extern "C" fn forget_pid() {
+        PID.store(0, Ordering::Relaxed);
+    }
+
+    let pid = PID.load(Ordering::Relaxed);
+    if pid != 0 {
+        return pid;
+    }
+    // SAFETY: the handler only touches an atomic, which is fine in a forked child.
+    AT_FORK.call_once(|| unsafe {
+        libc::pthread_atfork(None, None, Some(forget_pid));
+    });
+    let pid = std::process::id();
+    PID.store(pid, Ordering::Relaxed);
+    pid
+}
+
+pub(crate) fn is_alive(pid: u32) -> bool {
+    // Common for producers in the same process, so spare the syscalls.
+    if pid == our_pid() {
+        return true;
+    }
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+
+    // Pidfd becomes readable once the process exits, even if it's a zombie that its parent didn't
+    // reap yet.
+    // SAFETY: `pidfd_open` doesn't touch memory.
+    let pidfd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if pidfd >= 0 {
+        let pidfd = pidfd as libc::c_int;
+        let mut pfd = libc::pollfd {
+            fd: pidfd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: we're passing one valid pollfd, and close the pidfd we own.
+        let res = unsafe {
+            let res = libc::poll(&mut pfd, 1, 0);
+            libc::close(pidfd);
+            res
+        };
+        return res == 0;
+    }
+    if io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH) {
+        return false;
+    }
+
+    // No pidfds on this kernel, so zombies are considered alive.
+    // SAFETY: signal 0 only checks whether the process exists.
+    let res = unsafe { libc::kill(pid, 0) };
+    // `EPERM` means that process exists, but belongs to someone else.
+    res == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
 }
 
 impl Drop for ShmemFutexGuard<'_> {
     fn drop(&mut self) {
-        if self.futex.swap(0, Ordering::Release) == 2 {
+        if self.futex.swap(0, Ordering::Release) & CONTENDED != 0 {
             futex_wake(self.futex, 1);
         }
     }
 }
 
-fn futex_wake(futex: &AtomicU32, count: u32) {
+pub(crate) fn futex_wake(futex: &AtomicU32, count: u32) {
     // SAFETY: futex operations are safe and we're passing all the right arguments.
     unsafe {
         libc::syscall(libc::SYS_futex, futex, libc::FUTEX_WAKE, count);
     }
 }
 
-fn futex_wait(futex: &AtomicU32, expected: u32) {
+/// Fails only with [`io::ErrorKind::TimedOut`]. Other errors mean either a spurious wakeup or that
+/// the value already changed, so they're ignored.
+pub(crate) fn futex_wait(
+    futex: &AtomicU32,
+    expected: u32,
+    timeout: Option<Duration>,
+) -> io::Result<()> {
+    let timespec = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+        tv_nsec: timeout.subsec_nanos().into(),
+    });
     // SAFETY: futex operations are safe and we're passing all the right arguments.
-    unsafe {
+    let res = unsafe {
         libc::syscall(
             libc::SYS_futex,
             futex,
             libc::FUTEX_WAIT,
             expected,
-            ptr::null::<libc::timespec>(),
-        );
+            timespec
+                .as_ref()
+                .map_or(ptr::null(), |timespec| timespec as *const libc::timespec),
+        )
+    };
+
+    if res != 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ETIMEDOUT) {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io, mem,
+        sync::atomic::Ordering,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{ShmemFutexControl, CONTENDED};
+    use crate::{
+        control::{Control, Side},
+        handshake::NamedFileHandshakeResult,
+        testing::{dead_pid, open, recv, send, try_recv, TempPath, TestQueue},
+        ShmemFutexControlConfig,
+    };
+
+    const RECOVER: ShmemFutexControlConfig = ShmemFutexControlConfig {
+        spin_on_wait: 0,
+        recover: true,
+    };
+
+    /// Sends three messages and receives one, closes the queue, then leaves it as if both peers
+    /// crashed in the middle of sending.
+    fn crash(path: &TempPath) {
+        let sender = open(path, 4096, ShmemFutexControlConfig::default());
+        let receiver = open(path, 4096, ShmemFutexControlConfig::default());
+        for message in [b"one", b"two", b"six"] {
+            send(&sender, message).unwrap();
+        }
+        assert_eq!(recv(&receiver).unwrap(), b"one");
+        sender.close().unwrap();
+        mem::forget(Control::<NamedFileHandshakeResult>::lock(
+            &sender.control,
+            Side::Right,
+        ));
+    }
+
+    /// Waits for the offset of `side` to change until it fails.
+    fn wait_for(control: &ShmemFutexControl, side: Side, timeout: Duration) -> io::Error {
+        let deadline = Instant::now() + timeout;
+        let offset = Control::<NamedFileHandshakeResult>::load_offset(control, side);
+        loop {
+            let res =
+                Control::<NamedFileHandshakeResult>::wait(control, side, offset, Some(deadline));
+            if let Err(err) = res {
+                return err;
+            }
+        }
+    }
+
+    fn reopen(path: &TempPath, config: ShmemFutexControlConfig) -> (TestQueue, TestQueue) {
+        let owner = open(path, 4096, config);
+        let peer = open(path, 4096, ShmemFutexControlConfig::default());
+        (owner, peer)
+    }
+
+    #[test]
+    fn recovery_keeps_unconsumed_messages() {
+        let path = TempPath::new();
+        crash(&path);
+
+        let (sender, receiver) = reopen(&path, RECOVER);
+        assert_eq!(sender.epoch(), 1);
+        assert_eq!(receiver.epoch(), 1);
+        assert_eq!(recv(&receiver).unwrap(), b"two");
+        assert_eq!(recv(&receiver).unwrap(), b"six");
+        // Queue closed before the crash is open again.
+        let err = try_recv(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        // Lock left behind by the crashed sender is released.
+        send(&sender, b"ten").unwrap();
+        assert_eq!(recv(&receiver).unwrap(), b"ten");
+    }
+
+    #[test]
+    fn queue_is_reset_without_recovery() {
+        let path = TempPath::new();
+        crash(&path);
+
+        let (sender, receiver) = reopen(&path, ShmemFutexControlConfig::default());
+        assert_eq!(sender.epoch(), 0);
+        let err = try_recv(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        send(&sender, b"ten").unwrap();
+        assert_eq!(recv(&receiver).unwrap(), b"ten");
+    }
+
+    #[test]
+    fn queue_with_broken_offsets_is_reset() {
+        let path = TempPath::new();
+        {
+            let queue = open(&path, 4096, ShmemFutexControlConfig::default());
+            let header = queue.control.header();
+            header.left.store_offset(4096, Ordering::Relaxed);
+            header.right.store_offset(0, Ordering::Relaxed);
+            mem::forget(Control::<NamedFileHandshakeResult>::lock(
+                &queue.control,
+                Side::Right,
+            ));
+        }
+
+        let (sender, receiver) = reopen(&path, RECOVER);
+        assert_eq!(sender.epoch(), 0);
+        let err = try_recv(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn dead_workers_are_noticed() {
+        let path = TempPath::new();
+        let queue = open(&path, 4096, ShmemFutexControlConfig::default());
+        let control = &queue.control;
+        for _ in 0..2 {
+            Control::<NamedFileHandshakeResult>::attach_shared(control, Side::Left).unwrap();
+        }
+        let workers = &control.header().left_shared[..2];
+
+        // One worker dying without detaching is not enough.
+        workers[0].store(dead_pid(), Ordering::SeqCst);
+        let err = wait_for(control, Side::Left, Duration::from_millis(250));
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        workers[1].store(dead_pid(), Ordering::SeqCst);
+        let err = wait_for(control, Side::Left, Duration::from_secs(5));
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        // Receiver can take over the side they left behind.
+        Control::<NamedFileHandshakeResult>::claim(control, Side::Left).unwrap();
+    }
+
+    #[test]
+    fn locks_of_dead_processes_are_taken_over() {
+        let path = TempPath::new();
+        let queue = open(&path, 4096, ShmemFutexControlConfig::default());
+        let header = queue.control.header();
+        header.right.lock.store(dead_pid(), Ordering::Relaxed);
+        header
+            .left
+            .lock
+            .store(dead_pid() | CONTENDED, Ordering::Relaxed);
+
+        send(&queue, b"one").unwrap();
+        assert_eq!(recv(&queue).unwrap(), b"one");
+    }
+
+    #[test]
+    fn waiters_notice_peers_that_claim_later() {
+        let path = TempPath::new();
+        let queue = open(&path, 4096, ShmemFutexControlConfig::default());
+        let control = &queue.control;
+
+        thread::scope(|scope| {
+            let waiter = scope.spawn(|| wait_for(control, Side::Right, Duration::from_secs(5)));
+            while control.header().right_waiters.load(Ordering::SeqCst) == 0 {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(10));
+            // Sender claims the side after the receiver started waiting, and then dies.
+            Control::<NamedFileHandshakeResult>::claim(control, Side::Right).unwrap();
+            thread::sleep(Duration::from_millis(10));
+            control
+                .header()
+                .right_pid
+                .store(dead_pid(), Ordering::SeqCst);
+            assert_eq!(waiter.join().unwrap().kind(), io::ErrorKind::BrokenPipe);
+        });
     }
 }
@@ -0,0 +1,140 @@
+use std::{io, sync::Arc};
+
+use crate::{
+    handshake::HandshakeResult, mmap::get_page_size, Control, MemeQueue, MemeReceiver, MemeSender,
+    Ring,
+};
+
+/// Two queues going in opposite directions, sharing a single handshake.
+///
+/// Shared object is laid out as two queues one after another: header page and ring of the first
+/// queue, then header page and ring of the second one. Owner sends into the first queue and
+/// receives from the second one, the other peer does the opposite.
+pub struct MemeDuplex<H, C: Control<H>> {
+    sender: MemeSender<H, C>,
+    receiver: MemeReceiver<H, C>,
+}
+
+impl<H: HandshakeResult, C: Control<H>> MemeDuplex<H, C> {
+    /// Queue size to pass to a handshake to get two rings of `ring_size` bytes.
+    /// `ring_size` is rounded up to the next multiple of page size.
+    pub fn handshake_size(ring_size: usize) -> usize {
+        let page_size = get_page_size();
+        2 * ring_size.next_multiple_of(page_size) + page_size
+    }
+
+    pub fn new(handshake_result: H) -> io::Result<Self>
+    where
+        C::Config: Default + Clone,
+    {
+        Self::with_config(handshake_result, C::Config::default())
+    }
+
+    pub fn with_config(mut handshake_result: H, config: C::Config) -> io::Result<Self>
+    where
+        C::Config: Clone,
+    {
+        let page_size = get_page_size();
+        let ring_size = handshake_result
+            .queue_size()
+            .checked_sub(page_size)
+            .map(|size| size / 2)
+            .filter(|size| *size > 0 && *size % page_size == 0)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "queue size is not suitable for a duplex, see `MemeDuplex::handshake_size()`",
+                )
+            })?;
+
+        let first = Ring::new(&mut handshake_result, 0, ring_size, config.clone())?;
+        let second = Ring::new(
+            &mut handshake_result,
+            page_size + ring_size,
+            ring_size,
+            config,
+        )?;
+        let (send_ring, recv_ring) = if handshake_result.is_owner() {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        handshake_result.mark_ready()?;
+
+        let handshake_result = Arc::new(handshake_result);
+        let send_queue = MemeQueue::from_ring(send_ring, Arc::clone(&handshake_result));
+        let recv_queue = MemeQueue::from_ring(recv_ring, handshake_result);
+        Ok(Self {
+            sender: MemeSender::from_queue(Arc::new(send_queue))?,
+            receiver: MemeReceiver::from_queue(Arc::new(recv_queue))?,
+        })
+    }
+}
+
+impl<H, C: Control<H>> MemeDuplex<H, C> {
+    pub fn sender(&self) -> &MemeSender<H, C> {
+        &self.sender
+    }
+
+    pub fn receiver(&self) -> &MemeReceiver<H, C> {
+        &self.receiver
+    }
+
+    pub fn into_split(self) -> (MemeSender<H, C>, MemeReceiver<H, C>) {
+        (self.sender, self.receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write as _},
+        path::Path,
+    };
+
+    use super::MemeDuplex;
+    use crate::{
+        handshake::{named_file, NamedFileHandshakeResult},
+        testing::TempPath,
+        ShmemFutexControl,
+    };
+
+    type Duplex = MemeDuplex<NamedFileHandshakeResult, ShmemFutexControl>;
+
+    fn open(path: &Path) -> Duplex {
+        let size = Duplex::handshake_size(4096);
+        // SAFETY: test files are only used through queues.
+        MemeDuplex::new(unsafe { named_file(path, size) }.unwrap()).unwrap()
+    }
+
+    fn send(duplex: &Duplex, buf: &[u8]) {
+        duplex
+            .sender()
+            .send(|writer| writer.write_all(buf))
+            .unwrap();
+    }
+
+    fn recv(duplex: &Duplex) -> Vec<u8> {
+        duplex
+            .receiver()
+            .recv(|buf| io::Result::Ok(buf.to_vec()))
+            .unwrap()
+    }
+
+    #[test]
+    fn peers_talk_both_ways() {
+        let path = TempPath::new();
+        let owner = open(&path);
+        let peer = open(&path);
+
+        // Many times the ring in each direction.
+        for idx in 0..1000_u32 {
+            let ping = idx.to_le_bytes().repeat(idx as usize % 20);
+            send(&owner, &ping);
+            assert_eq!(recv(&peer), ping);
+            let pong = ping.repeat(2);
+            send(&peer, &pong);
+            assert_eq!(recv(&owner), pong);
+        }
+    }
+}
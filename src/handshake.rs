@@ -1,4 +1,6 @@
-use std::{io, os::fd::RawFd};
+use std::{error::Error, fmt, fs::File, io, os::fd::RawFd};
+
+use crate::{mmap::get_page_size, preamble};
 
 mod named_file;
 pub use named_file::{named_file, NamedFileHandshakeResult};
@@ -6,7 +8,10 @@ pub use named_file::{named_file, NamedFileHandshakeResult};
 #[cfg(feature = "handshake_uds_memfd")]
 mod uds_memfd;
 #[cfg(feature = "handshake_uds_memfd")]
-pub use uds_memfd::{uds_memfd, UdsMemfdHandshakeResult};
+pub use uds_memfd::{
+    uds_memfd, uds_memfd_connect, uds_memfd_listener, UdsMemfdHandshakeResult, UdsMemfdListener,
+    UdsMemfdListenerHandshakeResult,
+};
 
 /// # Safety
 /// 1. `shmem_fd` must point to a mmapable object of size `page_size + queue_size`.
@@ -24,3 +29,169 @@ pub trait ExchangeFd {
     fn send_fd(&mut self, fd: RawFd) -> io::Result<()>;
     fn recv_fd(&mut self) -> io::Result<RawFd>;
 }
+
+/// Why a handshake failed. Converts into an [`io::Error`] of kind
+/// [`io::ErrorKind::InvalidData`], unless it's [`HandshakeError::Io`].
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// Queue size is not a multiple of page size.
+    InvalidSize {
+        queue_size: usize,
+        page_size: usize,
+    },
+    /// Shared object doesn't have space for the header page and at least one page of the queue.
+    TooSmall {
+        file_size: u64,
+        page_size: usize,
+    },
+    /// Peer set up the queue differently, e.g. it's built with an incompatible version of this
+    /// crate.
+    ProtocolMismatch(String),
+    /// Queue can't be handed out to peers yet, since it wasn't created from the owner handshake
+    /// result. Returned by `UdsMemfdListener::accept()`.
+    NotReady,
+    Io(io::Error),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidSize {
+                queue_size,
+                page_size,
+            } => write!(
+                f,
+                "queue size ({queue_size}) is not a multiple of page size ({page_size})"
+            ),
+            Self::TooSmall {
+                file_size,
+                page_size,
+            } => write!(
+                f,
+                "queue file size ({file_size}) must be greater than page size ({page_size})"
+            ),
+            Self::ProtocolMismatch(message) => write!(f, "protocol mismatch: {message}"),
+            Self::NotReady => f.write_str("queue is not ready yet"),
+            Self::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for HandshakeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<HandshakeError> for io::Error {
+    fn from(err: HandshakeError) -> Self {
+        match err {
+            HandshakeError::Io(err) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
+/// Size of the queue in an existing shared `file` that we connect to, after checking that the
+/// file can hold one and that its header was set up by a compatible peer.
+fn existing_queue_size(file: &File) -> Result<usize, HandshakeError> {
+    let page_size = get_page_size();
+    let file_size = file.metadata()?.len();
+    let queue_size = usize::try_from(file_size)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "queue file is too large"))?
+        .checked_sub(page_size)
+        .filter(|size| *size > 0)
+        .ok_or(HandshakeError::TooSmall {
+            file_size,
+            page_size,
+        })?;
+
+    if queue_size % page_size != 0 {
+        return Err(HandshakeError::InvalidSize {
+            queue_size,
+            page_size,
+        });
+    }
+
+    preamble::check_file(file, queue_size)?;
+    Ok(queue_size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io, os::fd::AsRawFd as _};
+
+    use super::{named_file, HandshakeError};
+    use crate::{mmap::get_page_size, testing::TempPath};
+
+    /// Connects to a queue in a file of `file_size` zero bytes that someone else owns.
+    fn connect_to_file(file_size: usize) -> HandshakeError {
+        let path = TempPath::new();
+        let file = File::create(&*path).unwrap();
+        file.set_len(file_size as u64).unwrap();
+        // Shared lock means that the owner is done setting the queue up.
+        // SAFETY: `flock` is safe and we're passing valid fd + operation.
+        assert_eq!(unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH) }, 0);
+
+        // SAFETY: test files are only used through queues.
+        let Err(err) = (unsafe { named_file(&*path, 4096) }) else {
+            panic!("connected to a broken queue");
+        };
+        err
+    }
+
+    #[test]
+    fn file_without_queue_is_too_small() {
+        let page_size = get_page_size();
+        let err = connect_to_file(page_size);
+        assert!(matches!(
+            err,
+            HandshakeError::TooSmall { file_size, .. } if file_size == page_size as u64,
+        ));
+    }
+
+    #[test]
+    fn unaligned_file_has_invalid_size() {
+        let page_size = get_page_size();
+        let err = connect_to_file(2 * page_size + 100);
+        assert!(matches!(
+            err,
+            HandshakeError::InvalidSize { queue_size, .. } if queue_size == page_size + 100,
+        ));
+    }
+
+    #[test]
+    fn file_without_header_is_protocol_mismatch() {
+        let err = connect_to_file(2 * get_page_size());
+        assert!(matches!(err, HandshakeError::ProtocolMismatch(_)));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn missing_directory_is_io_error() {
+        let path = TempPath::new().join("queue");
+        // SAFETY: nothing can be there.
+        let Err(err) = (unsafe { named_file(&path, 4096) }) else {
+            panic!("created a queue in a missing directory");
+        };
+        assert!(matches!(&err, HandshakeError::Io(err) if err.kind() == io::ErrorKind::NotFound));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::NotFound);
+    }
+
+    #[cfg(feature = "handshake_uds_memfd")]
+    #[test]
+    fn listener_is_not_ready_before_queue_is_created() {
+        let path = TempPath::new();
+        let (_handshake_result, listener) = super::uds_memfd_listener(&*path, 4096).unwrap();
+        assert!(matches!(listener.accept(), Err(HandshakeError::NotReady)));
+    }
+}
@@ -5,7 +5,10 @@ use std::{
     path::Path,
 };
 
-use crate::{handshake::HandshakeResult, mmap::get_page_size};
+use crate::{
+    handshake::{existing_queue_size, HandshakeError, HandshakeResult},
+    mmap::get_page_size,
+};
 
 pub struct NamedFileHandshakeResult {
     file: File,
@@ -58,15 +61,22 @@ impl Drop for NamedFileHandshakeResult {
 /// `queue_size` is only relevant when we end up creating the queue. If we end up connecting,
 /// existing queue size is used. `queue_size` will be rounded up to the next multiple of page size.
 ///
-/// # Panics
-/// If we connect to the queue and it has invalid size.
+/// If we connect to the queue, fails with [`HandshakeError::InvalidSize`] or
+/// [`HandshakeError::TooSmall`] if the file has invalid size, and with
+/// [`HandshakeError::ProtocolMismatch`] if the queue was set up by an incompatible version of this
+/// crate, or wasn't set up at all.
+///
+/// If everyone using the queue crashed, the next process to open it becomes the owner again. Pass
+/// the same `queue_size` and enable [`ShmemFutexControlConfig::recover`] to keep the messages.
+///
+/// [`ShmemFutexControlConfig::recover`]: crate::ShmemFutexControlConfig::recover
 ///
 /// # Safety
 /// This is inherently unsafe because any external modifications to the file would lead to a data race.
 pub unsafe fn named_file(
     path: impl AsRef<Path>,
     mut queue_size: usize,
-) -> io::Result<NamedFileHandshakeResult> {
+) -> Result<NamedFileHandshakeResult, HandshakeError> {
     let page_size = get_page_size();
     queue_size = queue_size.next_multiple_of(page_size);
 
@@ -82,7 +92,7 @@ pub unsafe fn named_file(
     if flock_result != 0 {
         let err = io::Error::last_os_error();
         if err.kind() != io::ErrorKind::WouldBlock {
-            return Err(err);
+            return Err(err.into());
         }
     }
 
@@ -94,17 +104,10 @@ pub unsafe fn named_file(
         // SAFETY: `flock` is safe and we're passing valid fd + operation.
         let flock_result = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_SH) };
         if flock_result != 0 {
-            return Err(io::Error::last_os_error());
+            return Err(io::Error::last_os_error().into());
         }
 
-        queue_size = usize::try_from(file.metadata()?.len())
-            .expect("queue file size must fit in usize")
-            .checked_sub(page_size)
-            .expect("queue file size must be greater than page size");
-
-        if queue_size % page_size != 0 {
-            panic!("queue size ({queue_size}) is not a multiple of page size ({page_size})");
-        }
+        queue_size = existing_queue_size(&file)?;
     }
 
     Ok(NamedFileHandshakeResult {
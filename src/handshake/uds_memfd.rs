@@ -6,7 +6,11 @@ use std::{
         fd::{AsRawFd as _, FromRawFd as _, RawFd},
         unix::net::{UnixListener, UnixStream},
     },
-    path::Path,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
 use nix::{
@@ -15,7 +19,7 @@ use nix::{
 };
 
 use crate::{
-    handshake::{ExchangeFd, HandshakeResult},
+    handshake::{existing_queue_size, ExchangeFd, HandshakeError, HandshakeResult},
     mmap::get_page_size,
 };
 
@@ -81,13 +85,19 @@ impl ExchangeFd for UdsMemfdHandshakeResult {
     }
 }
 
+/// Creates a queue and sends it to the peer that connects to `uds_path`, or connects to the peer
+/// that listens there and receives its queue.
+///
+/// If we receive the queue, fails with [`HandshakeError::InvalidSize`] or
+/// [`HandshakeError::TooSmall`] if it has invalid size, and with
+/// [`HandshakeError::ProtocolMismatch`] if the peer doesn't follow the protocol or set the queue
+/// up with an incompatible version of this crate.
 // TODO: explain safety considerations
 pub fn uds_memfd(
     uds_path: impl AsRef<Path>,
     mut queue_size: usize,
-) -> io::Result<UdsMemfdHandshakeResult> {
-    let page_size = get_page_size();
-    queue_size = queue_size.next_multiple_of(page_size);
+) -> Result<UdsMemfdHandshakeResult, HandshakeError> {
+    queue_size = queue_size.next_multiple_of(get_page_size());
 
     let (stream, owner) = match UnixListener::bind(&uds_path) {
         Ok(listener) => {
@@ -100,22 +110,12 @@ pub fn uds_memfd(
             fs::remove_file(&uds_path)?;
             (stream, false)
         }
-        Err(err) => return Err(err),
+        Err(err) => return Err(err.into()),
     };
 
     if owner {
-        // SAFETY: `name` points to a valid NULL-terminated C string.
-        let memfd = unsafe { libc::memfd_create(b"memequeue\0".as_ptr().cast(), 0) };
-        if memfd < 0 {
-            return Err(io::Error::last_os_error());
-        }
-
-        // SAFETY: memfd behaves like a regular file.
-        let file = unsafe { File::from_raw_fd(memfd) };
-        file.set_len((page_size + queue_size) as u64)?;
-
         Ok(UdsMemfdHandshakeResult {
-            file,
+            file: create_memfd(queue_size)?,
             owner,
             queue_size,
             stream,
@@ -123,46 +123,158 @@ pub fn uds_memfd(
             recv_fd_queue: VecDeque::new(),
         })
     } else {
-        let mut payload_buf = [0; PAYLOAD_BUF_SIZE];
-        let mut exchange_fd_counter = 0;
-        let mut recv_fd_queue = VecDeque::new();
-        let memfd = loop {
-            let (raw_fd, payload) = recv_fd(stream.as_raw_fd(), &mut payload_buf)?;
-            if payload == NEGOTIATION_MESSAGE {
-                break raw_fd;
-            } else if payload == usize::to_le_bytes(exchange_fd_counter + 1) {
-                recv_fd_queue.push_back(raw_fd);
-                exchange_fd_counter += 1;
-            } else {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("unexpected message payload: `{payload:?}`"),
-                ));
-            }
-        };
+        receive_queue(stream)
+    }
+}
 
-        // SAFETY: memfd behaves like a regular file, and we believe that other part is honest.
-        let file = unsafe { File::from_raw_fd(memfd) };
+/// Owner side of [`uds_memfd_listener()`].
+pub struct UdsMemfdListenerHandshakeResult {
+    file: File,
+    queue_size: usize,
+    ready: Arc<AtomicBool>,
+}
 
-        // TODO: probably should be deduped with named_file
-        queue_size = usize::try_from(file.metadata()?.len())
-            .expect("queue file size must fit in usize")
-            .checked_sub(page_size)
-            .expect("queue file size must be greater than page size");
+// TODO: safety
+unsafe impl HandshakeResult for UdsMemfdListenerHandshakeResult {
+    fn shmem_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    fn is_owner(&self) -> bool {
+        true
+    }
+
+    fn queue_size(&self) -> usize {
+        self.queue_size
+    }
+
+    fn mark_ready(&mut self) -> io::Result<()> {
+        self.ready.store(true, Ordering::Release);
+        Ok(())
+    }
+}
+
+/// Hands the queue out to any number of peers, see [`uds_memfd_listener()`].
+pub struct UdsMemfdListener {
+    listener: UnixListener,
+    path: PathBuf,
+    file: File,
+    ready: Arc<AtomicBool>,
+}
 
-        if queue_size % page_size != 0 {
-            panic!("queue size ({queue_size}) is not a multiple of page size ({page_size})");
+impl UdsMemfdListener {
+    /// Waits for a peer to connect and sends it the queue.
+    ///
+    /// Fails with [`HandshakeError::NotReady`] if the queue wasn't created from the owner
+    /// handshake result yet.
+    pub fn accept(&self) -> Result<(), HandshakeError> {
+        if !self.ready.load(Ordering::Acquire) {
+            return Err(HandshakeError::NotReady);
         }
 
-        Ok(UdsMemfdHandshakeResult {
+        let (stream, _peer_addr) = self.listener.accept()?;
+        send_fd(
+            stream.as_raw_fd(),
+            self.file.as_raw_fd(),
+            NEGOTIATION_MESSAGE,
+        )?;
+        Ok(())
+    }
+}
+
+impl Drop for UdsMemfdListener {
+    fn drop(&mut self) {
+        // Nobody can connect anymore, so the socket is useless.
+        let _res = fs::remove_file(&self.path);
+    }
+}
+
+/// Like [`uds_memfd()`], but always creates the queue and lets any number of peers connect to it
+/// with [`uds_memfd_connect()`], e.g. workers of a [`MemeWorker`](crate::MemeWorker) queue.
+///
+/// Since there's no single peer to exchange fds with, this doesn't work with
+/// [`EventFdControl`](crate::EventFdControl).
+pub fn uds_memfd_listener(
+    uds_path: impl AsRef<Path>,
+    mut queue_size: usize,
+) -> Result<(UdsMemfdListenerHandshakeResult, UdsMemfdListener), HandshakeError> {
+    queue_size = queue_size.next_multiple_of(get_page_size());
+
+    let listener = UnixListener::bind(&uds_path)?;
+    let file = create_memfd(queue_size)?;
+    let ready = Arc::new(AtomicBool::new(false));
+    let listener = UdsMemfdListener {
+        listener,
+        path: uds_path.as_ref().to_owned(),
+        file: file.try_clone()?,
+        ready: Arc::clone(&ready),
+    };
+
+    Ok((
+        UdsMemfdListenerHandshakeResult {
             file,
-            owner,
             queue_size,
-            stream,
-            exchange_fd_counter,
-            recv_fd_queue,
-        })
+            ready,
+        },
+        listener,
+    ))
+}
+
+/// Connects to a queue created by [`uds_memfd_listener()`]. Unlike [`uds_memfd()`], this never
+/// creates the queue and doesn't remove the socket, so other peers can connect too.
+///
+/// Fails the same way as [`uds_memfd()`] does when it receives the queue.
+pub fn uds_memfd_connect(
+    uds_path: impl AsRef<Path>,
+) -> Result<UdsMemfdHandshakeResult, HandshakeError> {
+    receive_queue(UnixStream::connect(uds_path)?)
+}
+
+fn create_memfd(queue_size: usize) -> io::Result<File> {
+    // SAFETY: `name` points to a valid NULL-terminated C string.
+    let memfd = unsafe { libc::memfd_create(c"memequeue".as_ptr(), 0) };
+    if memfd < 0 {
+        return Err(io::Error::last_os_error());
     }
+
+    // SAFETY: memfd behaves like a regular file.
+    let file = unsafe { File::from_raw_fd(memfd) };
+    file.set_len((get_page_size() + queue_size) as u64)?;
+    Ok(file)
+}
+
+/// Non-owner side of the handshake: waits for the owner to send the queue.
+fn receive_queue(stream: UnixStream) -> Result<UdsMemfdHandshakeResult, HandshakeError> {
+    let mut payload_buf = [0; PAYLOAD_BUF_SIZE];
+    let mut exchange_fd_counter = 0;
+    let mut recv_fd_queue = VecDeque::new();
+    let memfd = loop {
+        let (raw_fd, payload) = recv_fd(stream.as_raw_fd(), &mut payload_buf)?;
+        if payload == NEGOTIATION_MESSAGE {
+            break raw_fd;
+        } else if payload == usize::to_le_bytes(exchange_fd_counter + 1) {
+            recv_fd_queue.push_back(raw_fd);
+            exchange_fd_counter += 1;
+        } else {
+            return Err(HandshakeError::ProtocolMismatch(format!(
+                "unexpected message payload: `{payload:?}`"
+            )));
+        }
+    };
+
+    // SAFETY: memfd behaves like a regular file, and we believe that other part is honest.
+    let file = unsafe { File::from_raw_fd(memfd) };
+
+    let queue_size = existing_queue_size(&file)?;
+
+    Ok(UdsMemfdHandshakeResult {
+        file,
+        owner: false,
+        queue_size,
+        stream,
+        exchange_fd_counter,
+        recv_fd_queue,
+    })
 }
 
 fn send_fd(send_to: RawFd, to_send: RawFd, payload: &[u8]) -> io::Result<()> {
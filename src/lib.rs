@@ -2,17 +2,67 @@
 
 use std::{
     io::{self, Write},
-    mem, slice,
+    mem,
+    ops::{Deref, DerefMut},
+    slice,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
+#[cfg(feature = "async")]
+use crate::control::AsyncWaiter;
+pub use crate::control::Offset;
+#[cfg(feature = "async")]
+pub use crate::control::{AsyncControl, WaitState};
 pub use crate::control::{
     Control, EventFdControl, EventFdControlConfig, ShmemFutexControl, ShmemFutexControlConfig,
 };
 use crate::{control::Side, handshake::HandshakeResult, mmap::Mmap};
 
+#[cfg(feature = "rkyv")]
+mod archived;
+pub mod broadcast;
 mod control;
+mod duplex;
 pub mod handshake;
 mod mmap;
+mod preamble;
+mod producer;
+mod split;
+#[cfg(test)]
+mod testing;
+mod worker;
+pub use duplex::MemeDuplex;
+pub use producer::MemeProducer;
+pub use split::{MemeReceiver, MemeSender};
+pub use worker::MemeWorker;
+
+#[cfg(feature = "futures")]
+mod stream;
+#[cfg(feature = "futures")]
+pub use stream::{RecvStream, SendSink};
+
+#[cfg(feature = "rkyv")]
+pub use archived::{ArchiveSerializer, ArchiveWriter};
+
+mod meta;
+pub use meta::MessageMeta;
+use meta::META_SIZE;
+
+mod verify;
+pub use verify::Corrupted;
+
+#[cfg(feature = "serde")]
+mod typed;
+#[cfg(feature = "bincode")]
+pub use typed::Bincode;
+#[cfg(feature = "postcard")]
+pub use typed::Postcard;
+#[cfg(feature = "serde")]
+pub use typed::{Codec, Encoded, TypedMemeQueue};
 
 #[cfg(feature = "stats")]
 pub mod stats;
@@ -20,14 +70,13 @@ pub mod stats;
 #[macro_export]
 macro_rules! debug_output {
     // ($($t:tt)*) => { eprintln!($($t)*) }
-    ($($t:tt)*) => {}
-    // ($($t:tt)*) => {
-    //     unsafe {
-    //         use std::fmt::Write as _;
-    //         $crate::DEBUG.clear();
-    //         $crate::DEBUG.write_fmt(format_args!($($t)*)).unwrap();
-    //     }
-    // }
+    ($($t:tt)*) => {}; // ($($t:tt)*) => {
+                       //     unsafe {
+                       //         use std::fmt::Write as _;
+                       //         $crate::DEBUG.clear();
+                       //         $crate::DEBUG.write_fmt(format_args!($($t)*)).unwrap();
+                       //     }
+                       // }
 }
 
 pub struct MemeQueue<H, C> {
@@ -35,7 +84,8 @@ pub struct MemeQueue<H, C> {
     control: C,
     left: Mmap,
     right: Mmap,
-    handshake_result: H,
+    // Shared between both queues of a `MemeDuplex`.
+    handshake_result: Arc<H>,
 }
 
 impl<H: HandshakeResult, C: Control<H>> MemeQueue<H, C> {
@@ -47,21 +97,54 @@ impl<H: HandshakeResult, C: Control<H>> MemeQueue<H, C> {
     }
 
     pub fn with_config(mut handshake_result: H, config: C::Config) -> io::Result<Self> {
-        // SAFETY: guaranteed by `HandshakeResult`s contract.
+        let queue_size = handshake_result.queue_size();
+        let ring = Ring::new(&mut handshake_result, 0, queue_size, config)?;
+        handshake_result.mark_ready()?;
+        Ok(Self::from_ring(ring, Arc::new(handshake_result)))
+    }
+
+    fn from_ring(ring: Ring<C>, handshake_result: Arc<H>) -> Self {
+        Self {
+            control: ring.control,
+            left: ring.left,
+            right: ring.right,
+            handshake_result,
+        }
+    }
+}
+
+/// Parts of a [`MemeQueue`] that are not shared with other queues.
+struct Ring<C> {
+    control: C,
+    left: Mmap,
+    right: Mmap,
+}
+
+impl<C> Ring<C> {
+    /// Maps a ring of `queue_size` bytes with header page at `header_offset` in the shared object.
+    fn new<H: HandshakeResult>(
+        handshake_result: &mut H,
+        header_offset: usize,
+        queue_size: usize,
+        config: C::Config,
+    ) -> io::Result<Self>
+    where
+        C: Control<H>,
+    {
+        check_offsets_fit(queue_size)?;
+        // SAFETY: guaranteed by `HandshakeResult`s contract and by the caller.
         let mmap::QueueMmaps {
             left,
             right,
             header,
         } = unsafe {
-            mmap::QueueMmaps::from_fd(&handshake_result.shmem_fd(), handshake_result.queue_size())?
+            mmap::QueueMmaps::from_fd(&handshake_result.shmem_fd(), header_offset, queue_size)?
         };
-        let control = C::new(config, header, &mut handshake_result)?;
-        handshake_result.mark_ready()?;
+        let control = C::new(config, header, queue_size, handshake_result)?;
         Ok(Self {
             control,
             left,
             right,
-            handshake_result,
         })
     }
 }
@@ -72,53 +155,298 @@ impl<H, C: Control<H>> MemeQueue<H, C> {
         self.control.stats()
     }
 
+    /// Number of times the queue was recovered after a crash, see
+    /// [`ShmemFutexControlConfig::recover`].
+    pub fn epoch(&self) -> u64 {
+        self.control.epoch()
+    }
+
+    /// Waits for a message and passes it to the callback as a slice pointing into shared memory.
+    ///
+    /// Once the sender closed the queue (see [`MemeQueue::close()`]) and every message is
+    /// received, fails with [`io::ErrorKind::UnexpectedEof`], like a read from a closed socket.
     pub fn recv<R, E, F>(&self, cb: F) -> Result<R, E>
     where
         F: FnOnce(&[u8]) -> Result<R, E>,
         E: From<io::Error>,
+    {
+        self.recv_until(None, |_meta, buf| cb(buf))
+    }
+
+    /// Like [`MemeQueue::recv()`], but fails with [`io::ErrorKind::TimedOut`] if there's no
+    /// message after `timeout`.
+    pub fn recv_timeout<R, E, F>(&self, timeout: Duration, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.recv_until(Instant::now().checked_add(timeout), |_meta, buf| cb(buf))
+    }
+
+    /// Like [`MemeQueue::recv()`], but fails with [`io::ErrorKind::TimedOut`] if there's no
+    /// message by `deadline`.
+    pub fn recv_deadline<R, E, F>(&self, deadline: Instant, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.recv_until(Some(deadline), |_meta, buf| cb(buf))
+    }
+
+    fn recv_until<R, E, F>(&self, deadline: Option<Instant>, mut cb: F) -> Result<R, E>
+    where
+        F: FnOnce(Option<MessageMeta>, &[u8]) -> Result<R, E>,
+        E: From<io::Error>,
     {
         loop {
-            let guard = self.control.lock(Side::Left);
-            let left_offset = self.control.load_offset(Side::Left);
-            let right_offset = {
-                let cached = self.control.cached_offset(Side::Right);
-                match cached {
-                    Some(cached) if cached > left_offset => cached,
-                    _ => self.control.sync_load_offset(Side::Right),
+            match self.recv_now(cb) {
+                Ok(res) => return res,
+                Err((returned_cb, right_offset)) => {
+                    cb = returned_cb;
+                    // Error safety: we're not in the middle of some operation,
+                    // so failing is OK.
+                    self.check_eof(right_offset)?;
+                    self.wait_right(right_offset, deadline)?;
                 }
-            };
+            }
+        }
+    }
+
+    /// Waits for the right offset to move from `right_offset`, see [`Control::wait()`]. If it
+    /// didn't, also publishes reservations of dead producers that might be holding it back.
+    fn wait_right(&self, right_offset: Offset, deadline: Option<Instant>) -> io::Result<()> {
+        self.control.wait(Side::Right, right_offset, deadline)?;
+        self.publish_abandoned(right_offset)
+    }
 
+    /// Like [`MemeQueue::recv()`], but fails with [`io::ErrorKind::WouldBlock`] instead of waiting
+    /// if the queue is empty and not closed.
+    pub fn try_recv<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        match self.recv_now(|_meta, buf| cb(buf)) {
+            Ok(res) => res,
+            Err((_cb, right_offset)) => {
+                self.check_eof(right_offset)?;
+                Err(io::Error::from(io::ErrorKind::WouldBlock).into())
+            }
+        }
+    }
+
+    /// Receives a message if there's one. Otherwise, gives the callback back along with the right
+    /// offset to wait on.
+    fn recv_now<R, E, F>(&self, cb: F) -> Result<Result<R, E>, (F, Offset)>
+    where
+        F: FnOnce(Option<MessageMeta>, &[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        let (guard, frame) = match self.next_message() {
+            Ok(Ok(message)) => message,
+            Ok(Err(right_offset)) => return Err((cb, right_offset)),
+            Err(err) => return Ok(Err(err.into())),
+        };
+        let res = cb(frame.meta, frame.message);
+        // Note: message is consumed even if callback failed. Use `.recv_guard()` to retry.
+        // Error safety: we already commited offset and will return soon regardless.
+        if let Err(err) = self.consume(guard, frame.end, frame.next_seq()) {
+            return Ok(Err(err.into()));
+        }
+        Ok(res)
+    }
+
+    /// Locks left side and finds the next message. If there's none, returns the right offset to
+    /// wait on.
+    ///
+    /// Fails with [`Corrupted`] if the frame doesn't pass verification.
+    fn next_message(&self) -> io::Result<Result<(C::LockGuard<'_>, Frame<'_>), Offset>> {
+        let guard = self.control.lock(Side::Left);
+        let left_offset = self.control.load_offset(Side::Left);
+        let right_offset = {
+            let cached = self.control.cached_offset(Side::Right);
+            match cached {
+                Some(cached) if cached > left_offset => cached,
+                _ => self.control.sync_load_offset(Side::Right),
+            }
+        };
+
+        if right_offset <= left_offset {
+            return Ok(Err(right_offset));
+        }
+
+        let expected_seq = self.control.expected_seq().load(Ordering::Relaxed);
+        // SAFETY: everything between the offsets is committed and we're holding left lock.
+        let frame_offset = unsafe { skip_padding(&self.left, left_offset, right_offset) };
+        let frame_offset = frame_offset.ok_or(Corrupted::header(expected_seq))?;
+        if frame_offset == right_offset {
+            // Only padding left by a producer that panicked, skip it and wait for more.
+            self.consume(guard, right_offset, None)?;
+            return Ok(Err(right_offset));
+        }
+        // SAFETY: same as above.
+        let frame = unsafe { read_frame(&self.left, frame_offset, right_offset) };
+        let frame = frame.ok_or(Corrupted::header(expected_seq))?;
+        if let Err(corrupted) = frame.verify(expected_seq) {
+            let (left_offset, expected_seq) = corrupted.resume(left_offset, &frame);
+            self.consume(guard, left_offset, Some(expected_seq))?;
+            return Err(corrupted.into());
+        }
+        Ok(Ok((guard, frame)))
+    }
+
+    /// Fails with [`io::ErrorKind::UnexpectedEof`] if the sender closed the queue and there's
+    /// nothing after `right_offset`.
+    fn check_eof(&self, right_offset: Offset) -> io::Result<()> {
+        if !self.control.is_closed() {
+            return Ok(());
+        }
+        // Sender commits everything before closing, so the offset we see now is final.
+        if self.control.sync_load_offset(Side::Right) == right_offset {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "queue is closed",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Commits messages found by [`MemeQueue::next_message()`] up to `new_left_offset`, unlocks
+    /// left side and notifies the other side. Receivers will expect `expected_seq` next, if it's
+    /// given.
+    fn consume(
+        &self,
+        guard: C::LockGuard<'_>,
+        new_left_offset: Offset,
+        expected_seq: Option<u64>,
+    ) -> io::Result<()> {
+        self.control.commit_offset(Side::Left, new_left_offset);
+        if let Some(expected_seq) = expected_seq {
+            self.control
+                .expected_seq()
+                .store(expected_seq, Ordering::Relaxed);
+        }
+        drop(guard);
+        debug_output!("notifying left about {}", new_left_offset);
+        self.control.notify(Side::Left)
+    }
+
+    /// Waits for messages and passes all messages that are currently in the queue (but no more
+    /// than `max`) to the callback at once. Only messages yielded by the iterator are consumed,
+    /// and they're committed with a single offset update and notification.
+    ///
+    /// Iterator stops at a frame that fails verification, and then this fails with [`Corrupted`].
+    pub fn recv_batch<R, E, F>(&self, max: usize, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut Messages<'_>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        let (guard, left_offset, right_offset) = loop {
+            let guard = self.control.lock(Side::Left);
+            let left_offset = self.control.load_offset(Side::Left);
+            // Don't use cached offset, since we want everything that's there.
+            let right_offset = self.control.sync_load_offset(Side::Right);
             if right_offset > left_offset {
-                debug_assert!((right_offset - left_offset) as usize > mem::size_of::<usize>());
-                // SAFETY: we keep offsets in-bounds
-                let slice = unsafe {
-                    let left_ptr = self.left.as_ptr().add(left_offset as usize);
-                    let size = left_ptr.cast::<usize>().read_unaligned();
-                    let data_ptr = left_ptr.add(mem::size_of::<usize>());
-                    let slice = slice::from_raw_parts(data_ptr, size);
-                    slice
-                };
-                let res = cb(slice);
-                // TODO: should we commit offset if callback failed?
-                self.control.commit_offset(
-                    Side::Left,
-                    left_offset + mem::size_of::<usize>() as u32 + slice.len() as u32,
-                );
-                drop(guard);
-                debug_output!("notifying left about {}", left_offset + mem::size_of::<usize>() as u32 + slice.len() as u32);
-                // Error safety: we already commited offset and will return soon regardless.
-                self.control.notify(Side::Left)?;
-                return res;
-            } else {
-                drop(guard);
-                // Error safety: we're not in the middle of some operation,
-                // so failing is OK.
-                self.control.wait(Side::Right, right_offset)?;
+                break (guard, left_offset, right_offset);
+            }
+            drop(guard);
+            self.check_eof(right_offset)?;
+            self.wait_right(right_offset, None)?;
+        };
+
+        let mut messages = Messages {
+            left: &self.left,
+            offset: left_offset,
+            end: right_offset,
+            remaining: max,
+            expected_seq: self.control.expected_seq().load(Ordering::Relaxed),
+            corrupted: None,
+        };
+        let res = cb(&mut messages);
+        // Error safety: we already commited offset and will return soon regardless.
+        self.consume(guard, messages.offset, Some(messages.expected_seq))?;
+        match messages.corrupted {
+            Some(corrupted) if res.is_ok() => Err(io::Error::from(corrupted).into()),
+            _ => res,
+        }
+    }
+
+    /// Waits for a message and returns a guard that allows to inspect it without consuming.
+    /// Message is consumed only when [`RecvGuard::commit()`] is called.
+    ///
+    /// Left lock is held until the guard is dropped, so the sender can't wrap around meanwhile.
+    pub fn recv_guard(&self) -> io::Result<RecvGuard<'_, H, C>> {
+        loop {
+            match self.next_message()? {
+                Ok((lock, frame)) => {
+                    return Ok(RecvGuard {
+                        queue: self,
+                        lock,
+                        frame,
+                    })
+                }
+                Err(right_offset) => {
+                    self.check_eof(right_offset)?;
+                    self.wait_right(right_offset, None)?;
+                }
             }
         }
     }
 
+    /// Marks the queue as closed and wakes the receiver. Messages that are already sent can still
+    /// be received, after that receives fail with [`io::ErrorKind::UnexpectedEof`].
+    ///
+    /// Sending after closing is allowed, but the receiver might have already seen the end.
+    pub fn close(&self) -> io::Result<()> {
+        // Wait for an in-flight send to commit, so receivers see everything before the flag.
+        let _guard = self.control.lock(Side::Right);
+        self.control.set_closed(true);
+        self.control.notify(Side::Right)
+    }
+
     pub fn send<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.send_until(None, cb)
+    }
+
+    /// Like [`MemeQueue::send()`], but writes fail with [`io::ErrorKind::TimedOut`] if there's
+    /// still no space after `timeout`. If callback propagates the error, nothing is sent.
+    pub fn send_timeout<R, E, F>(&self, timeout: Duration, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.send_until(Instant::now().checked_add(timeout), cb)
+    }
+
+    /// Like [`MemeQueue::send()`], but writes fail with [`io::ErrorKind::TimedOut`] if there's
+    /// still no space by `deadline`. If callback propagates the error, nothing is sent.
+    pub fn send_deadline<R, E, F>(&self, deadline: Instant, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.send_until(Some(deadline), cb)
+    }
+
+    fn send_until<R, E, F>(&self, deadline: Option<Instant>, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.send_frame_until(None, deadline, cb)
+    }
+
+    /// Like [`MemeQueue::send_until()`], but [`MessageMeta`] is recorded if there's a `tag`.
+    fn send_frame_until<R, E, F>(
+        &self,
+        tag: Option<u32>,
+        deadline: Option<Instant>,
+        cb: F,
+    ) -> Result<R, E>
     where
         F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
         E: From<io::Error>,
@@ -128,24 +456,697 @@ impl<H, C: Control<H>> MemeQueue<H, C> {
             queue: self,
             total_written: 0,
             right_offset: self.control.load_offset(Side::Right),
+            pending: 0,
+            deadline,
+        };
+        let prefix = match tag {
+            Some(_) => FRAME_HEADER_SIZE + META_SIZE,
+            None => MESSAGE_OFFSET,
         };
-        // Space for size
-        writer.write_all(&[0; mem::size_of::<usize>()])?;
+        // Space for size and metadata
+        io::copy(
+            &mut io::Read::take(io::repeat(0), prefix as u64),
+            &mut writer,
+        )?;
         let res = cb(&mut writer);
 
         if res.is_ok() {
-            let message_size = writer.total_written as usize - mem::size_of::<usize>();
-            let right_offset = writer.right_offset;
-            // SAFETY: we keep offsets in bounds
-            unsafe {
-                let right_ptr = self.left.as_ptr().add(right_offset as usize);
-                right_ptr.cast::<usize>().write_unaligned(message_size);
+            let message_size = writer.total_written as usize - prefix;
+            let meta = match tag {
+                Some(tag) => Some(self.next_meta(tag)),
+                None => self.default_meta(),
             };
-            self.control
-                .commit_offset(Side::Right, right_offset + writer.total_written);
             // Error safety: we commited offset and will return soon regardless
-            debug_output!("notifying right about {}", right_offset + writer.total_written);
-            self.control.notify(Side::Right)?;
+            self.commit_right(self.finish_frame(writer.right_offset, meta, message_size))?;
+        }
+
+        res
+    }
+
+    /// Sends several messages at once. They're committed with a single offset update and
+    /// notification when callback returns, so receiver sees either all of them or none.
+    /// If callback fails, nothing is sent.
+    ///
+    /// All messages in a batch together must fit into the queue.
+    pub fn send_batch<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut SendBatch<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        let _guard = self.control.lock(Side::Right);
+        let mut batch = SendBatch {
+            queue: self,
+            right_offset: self.control.load_offset(Side::Right),
+            len: 0,
+        };
+        let next_seq = self.control.next_seq().load(Ordering::Relaxed);
+        let res = cb(&mut batch);
+
+        if res.is_err() {
+            // Messages that are not sent must not leave a gap in sequence numbers.
+            self.control.next_seq().store(next_seq, Ordering::Relaxed);
+        } else if batch.len > 0 {
+            // Error safety: we commited offset and will return soon regardless
+            self.commit_right(batch.right_offset + batch.len)?;
+        }
+
+        res
+    }
+
+    /// Reserves space for a message of `len` bytes directly in the queue, waiting for it if
+    /// needed. Message is sent when [`SendGuard::commit()`] is called.
+    ///
+    /// Right lock is held until the guard is dropped.
+    pub fn send_reserve(&self, len: usize) -> io::Result<SendGuard<'_, H, C>> {
+        self.send_reserve_aligned(len, 1)
+    }
+
+    /// Like [`MemeQueue::send_reserve()`], but the message is placed at a multiple of `align`
+    /// bytes in shared memory, so it can be reinterpreted as a type with that alignment.
+    /// `align` must be a power of two no larger than page size.
+    ///
+    /// Gap before the message is filled with a padding frame that receivers skip.
+    pub fn send_reserve_aligned(
+        &self,
+        len: usize,
+        align: usize,
+    ) -> io::Result<SendGuard<'_, H, C>> {
+        if !align.is_power_of_two() || align > mmap::get_page_size() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "alignment must be a power of two no larger than page size",
+            ));
+        }
+
+        let lock = self.control.lock(Side::Right);
+        let mut right_offset = self.control.load_offset(Side::Right);
+        // Offsets are only ever moved by the ring size, which is a multiple of `align`.
+        let padding = padding(right_offset, MESSAGE_OFFSET, align);
+        let frame_size = self.frame_size(padding + len)?;
+        while let Err(left_offset) = self.try_reserve(&mut right_offset, frame_size) {
+            self.control.wait(Side::Left, left_offset, None)?;
+        }
+
+        if padding > 0 {
+            self.write_padding_header(right_offset, padding);
+        }
+        Ok(SendGuard {
+            queue: self,
+            _lock: lock,
+            right_offset: right_offset + padding as Offset,
+            len,
+        })
+    }
+
+    /// Sends a whole message without waiting. If there's not enough space for it right now, fails
+    /// with [`io::ErrorKind::WouldBlock`] without writing anything.
+    pub fn try_send(&self, buf: &[u8]) -> io::Result<()> {
+        self.send_now(buf)?
+            .map_err(|_left_offset| io::ErrorKind::WouldBlock.into())
+    }
+
+    /// Sends a whole message if there's enough space for it. Otherwise, returns the left offset to
+    /// wait on without writing anything.
+    fn send_now(&self, buf: &[u8]) -> io::Result<Result<(), Offset>> {
+        let frame_size = self.frame_size(buf.len())?;
+        let _guard = self.control.lock(Side::Right);
+        let mut right_offset = self.control.load_offset(Side::Right);
+        if let Err(left_offset) = self.try_reserve(&mut right_offset, frame_size) {
+            return Ok(Err(left_offset));
+        }
+        self.write_frame(right_offset, buf).map(Ok)
+    }
+
+    /// Makes sure that a frame of `frame_size` bytes fits after `right_offset`, fixing up offsets
+    /// if needed. If there's not enough space yet, returns the left offset to wait on.
+    ///
+    /// Right lock must be held.
+    fn try_reserve(&self, right_offset: &mut Offset, frame_size: usize) -> Result<(), Offset> {
+        let size = self.left.size();
+        let fits = |left_offset: Offset, right_offset: Offset| {
+            right_offset as usize + frame_size <= (left_offset as usize + size).min(2 * size)
+        };
+
+        if let Some(cached) = self.control.cached_offset(Side::Left) {
+            if fits(cached, *right_offset) {
+                return Ok(());
+            }
+        }
+
+        let left_offset = self.control.sync_load_offset(Side::Left);
+        if fits(left_offset, *right_offset) {
+            return Ok(());
+        }
+
+        if left_offset as usize >= size {
+            let _left_guard = self.control.lock(Side::Left);
+            let new_left_offset = self.control.load_offset(Side::Left) - size as Offset;
+            // With multiple producers, `right_offset` can be ahead of the committed one.
+            let committed_right_offset = self.control.load_offset(Side::Right) - size as Offset;
+            self.control
+                .fix_offsets(new_left_offset, committed_right_offset);
+            self.control.producers().shift(size as Offset);
+            *right_offset -= size as Offset;
+            if fits(new_left_offset, *right_offset) {
+                return Ok(());
+            }
+            return Err(new_left_offset);
+        }
+
+        Err(left_offset)
+    }
+
+    /// Writes a whole message at `right_offset`, commits it and notifies the other side.
+    ///
+    /// Right lock must be held and [`MemeQueue::try_reserve()`] must have succeeded.
+    fn write_frame(&self, right_offset: Offset, buf: &[u8]) -> io::Result<()> {
+        // SAFETY: space is reserved and we keep offsets in bounds.
+        unsafe {
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), self.message_ptr(right_offset), buf.len())
+        };
+        self.commit_frame(right_offset, buf.len())
+    }
+
+    /// Writes frame header for a message that's already in place, commits it and notifies the
+    /// other side.
+    ///
+    /// Right lock must be held.
+    fn commit_frame(&self, right_offset: Offset, message_size: usize) -> io::Result<()> {
+        let meta = self.default_meta();
+        self.commit_right(self.finish_frame(right_offset, meta, message_size))
+    }
+
+    /// Writes header and `meta` of a frame whose message is already in place, and returns the
+    /// offset of the next frame. With `verify` feature, metadata block also gets a checksum.
+    ///
+    /// Space for `meta` must be reserved before the message if there is one.
+    fn finish_frame(
+        &self,
+        offset: Offset,
+        meta: Option<MessageMeta>,
+        message_size: usize,
+    ) -> Offset {
+        let Some(meta) = meta else {
+            // SAFETY: we keep offsets in bounds
+            unsafe { write_frame_header(&self.left, offset, message_size as u64) };
+            return next_offset(offset, message_size);
+        };
+
+        let meta_ptr = self
+            .left
+            .as_ptr()
+            .wrapping_add(offset as usize + FRAME_HEADER_SIZE);
+        let mut header = META | message_size as u64;
+        let mut checksum = 0;
+        if cfg!(feature = "verify") {
+            // SAFETY: message is in place right after the metadata block.
+            let message = unsafe { slice::from_raw_parts(meta_ptr.add(META_SIZE), message_size) };
+            checksum = verify::checksum(message);
+            header |= CHECKSUM;
+        }
+        // SAFETY: we keep offsets in bounds
+        unsafe {
+            meta_ptr
+                .cast::<[u8; META_SIZE]>()
+                .write(meta.to_bytes(checksum));
+            write_frame_header(&self.left, offset, header);
+        }
+        next_offset(offset, META_SIZE + message_size)
+    }
+
+    /// Fills `padding` bytes at `offset` with a padding frame.
+    fn write_padding_header(&self, offset: Offset, padding: usize) {
+        let padding_size = (padding - FRAME_HEADER_SIZE) as u64;
+        // SAFETY: we keep offsets in bounds
+        unsafe { write_frame_header(&self.left, offset, PADDING | padding_size) };
+    }
+
+    fn commit_right(&self, new_right_offset: Offset) -> io::Result<()> {
+        self.control.commit_offset(Side::Right, new_right_offset);
+        debug_output!("notifying right about {}", new_right_offset);
+        self.control.notify(Side::Right)
+    }
+
+    /// Pointer to the message in a frame sent without a tag starting at `offset`.
+    fn message_ptr(&self, offset: Offset) -> *mut u8 {
+        self.left
+            .as_ptr()
+            .wrapping_add(offset as usize + MESSAGE_OFFSET)
+    }
+
+    #[allow(clippy::io_other_error)] // Kind is spelled out until it can be `StorageFull`.
+    fn frame_size(&self, message_size: usize) -> io::Result<usize> {
+        let frame_size = MESSAGE_OFFSET + message_size;
+        if frame_size > self.left.size() {
+            return Err(io::Error::new(
+                // TODO: should be `StorageFull`
+                io::ErrorKind::Other,
+                "tried to write too much",
+            ));
+        }
+        Ok(frame_size)
+    }
+}
+
+#[cfg(feature = "pod")]
+impl<H, C: Control<H>> MemeQueue<H, C> {
+    /// Sends a copy of `value`, aligned so that receiver can read it in place with
+    /// [`MemeQueue::recv_pod()`].
+    pub fn send_pod<T: bytemuck::NoUninit>(&self, value: &T) -> io::Result<()> {
+        let mut guard = self.send_reserve_aligned(mem::size_of::<T>(), mem::align_of::<T>())?;
+        guard.copy_from_slice(bytemuck::bytes_of(value));
+        guard.commit()
+    }
+
+    /// Receives a message as a reference to `T` in shared memory, without copying it.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if the message has the wrong size or alignment
+    /// for `T`. Message is consumed either way.
+    pub fn recv_pod<T, R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        T: bytemuck::AnyBitPattern,
+        F: FnOnce(&T) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.recv(|buf| {
+            let value = bytemuck::try_from_bytes(buf).map_err(|err| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "can't read message as `{}`: {err}",
+                        std::any::type_name::<T>()
+                    ),
+                )
+            })?;
+            cb(value)
+        })
+    }
+}
+
+#[cfg(feature = "async")]
+impl<H, C: AsyncControl<H>> MemeQueue<H, C> {
+    /// Async version of [`MemeQueue::recv()`]. Parks the task instead of the thread.
+    ///
+    /// Must be called from within a tokio runtime with timers enabled.
+    pub async fn recv_async<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        let mut cb = |_meta: Option<MessageMeta>, buf: &[u8]| cb(buf);
+        let mut waiter = AsyncWaiter::new(&self.control, Side::Right);
+        loop {
+            match self.recv_now(cb) {
+                Ok(res) => return res,
+                Err((returned_cb, right_offset)) => {
+                    cb = returned_cb;
+                    self.check_eof(right_offset)?;
+                    waiter.wait(right_offset).await?;
+                    self.publish_abandoned(right_offset)?;
+                }
+            }
+        }
+    }
+
+    /// Async version of [`MemeQueue::send()`]. Parks the task instead of the thread.
+    ///
+    /// Unlike [`MemeQueue::send()`], this takes the whole message at once, so we can wait until
+    /// there's enough space for it before writing anything. Right lock is only held while we check
+    /// for space and write, never while we wait: it blocks the thread, and other tasks on it might
+    /// need the lock too.
+    ///
+    /// Must be called from within a tokio runtime with timers enabled.
+    pub async fn send_async(&self, buf: &[u8]) -> io::Result<()> {
+        let mut waiter = AsyncWaiter::new(&self.control, Side::Left);
+        while let Err(left_offset) = self.send_now(buf)? {
+            waiter.wait(left_offset).await?;
+        }
+        Ok(())
+    }
+}
+
+// Frame layout is the same on every architecture, so 32-bit and 64-bit processes can share a
+// queue:
+//
+// | Bytes                | Contents                                                   |
+// |----------------------|------------------------------------------------------------|
+// | `0..8`               | Header: message size, little-endian `u64`, and flags       |
+// | `8..32`              | [`MessageMeta`], only if the `META` flag is set            |
+// | after that, `size`   | Message                                                    |
+//
+// Frames are not aligned, except when aligned messages were requested explicitly. With `verify`
+// feature, every frame has `META` and `CHECKSUM` flags set.
+
+/// Size of the frame header.
+const FRAME_HEADER_SIZE: usize = mem::size_of::<u64>();
+
+/// Where the message starts in frames sent without a tag.
+const MESSAGE_OFFSET: usize = if cfg!(feature = "verify") {
+    FRAME_HEADER_SIZE + META_SIZE
+} else {
+    FRAME_HEADER_SIZE
+};
+
+/// Identifies the frame layout above. It's recorded in the header page by the queue owner, so
+/// peers that would parse frames differently refuse to connect instead of misreading them. Must
+/// be changed whenever the layout changes.
+pub(crate) const FRAME_FORMAT: u32 = 3;
+
+/// Set in the frame header instead of the message size for padding frames, which exist only to
+/// align the next frame. The rest of the header is the size of the padding after it.
+const PADDING: u64 = 1 << 63;
+
+/// Set in the frame header if [`MessageMeta`] follows it.
+const META: u64 = 1 << 62;
+
+/// Set in the frame header along with `META` if the metadata block holds a checksum of the
+/// message.
+const CHECKSUM: u64 = 1 << 61;
+
+/// Part of the frame header that holds the size.
+const SIZE_MASK: u64 = !(PADDING | META | CHECKSUM);
+
+/// How much padding to put at `offset` so that the message of the next frame, which comes
+/// `prefix` bytes after the frame start, is aligned to `align`. Padding is either zero or large
+/// enough to fit a frame header.
+fn padding(offset: Offset, prefix: usize, align: usize) -> usize {
+    let mut padding = (align - (offset as usize + prefix) % align) % align;
+    while padding != 0 && padding < FRAME_HEADER_SIZE {
+        padding += align;
+    }
+    padding
+}
+
+/// Skips padding frames starting at `offset`, returning the offset of the first real frame, or
+/// `end` if there's nothing but padding. Returns `None` if a frame header is corrupted, i.e.
+/// padding doesn't end by `end`.
+///
+/// # Safety
+/// Everything between `offset` and `end` must be committed.
+unsafe fn skip_padding(left: &Mmap, mut offset: Offset, end: Offset) -> Option<Offset> {
+    loop {
+        if offset == end {
+            return Some(offset);
+        }
+        if ((end - offset) as usize) < FRAME_HEADER_SIZE {
+            return None;
+        }
+        // SAFETY: guaranteed by the caller.
+        let header = unsafe { read_frame_header(left, offset) };
+        if header & PADDING == 0 {
+            return Some(offset);
+        }
+        // Padding is committed together with the frame after it, unless it replaces a frame that
+        // a `MemeProducer` didn't finish.
+        let padding = usize::try_from(header & SIZE_MASK).ok()?;
+        if padding > (end - offset) as usize - FRAME_HEADER_SIZE {
+            return None;
+        }
+        offset = next_offset(offset, padding);
+    }
+}
+
+/// Offsets go up to twice the queue size, so they must fit into [`Offset`].
+fn check_offsets_fit(queue_size: usize) -> io::Result<()> {
+    if queue_size > Offset::MAX as usize / 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "queue is too large for 32-bit offsets, enable `offsets64` feature",
+        ));
+    }
+    Ok(())
+}
+
+/// Offset of the frame after the one at `offset`.
+fn next_offset(offset: Offset, message_size: usize) -> Offset {
+    offset + (FRAME_HEADER_SIZE + message_size) as Offset
+}
+
+/// Fails if the queue was created with a different [`FRAME_FORMAT`]. Queue owner records ours.
+pub(crate) fn check_frame_format(format: &AtomicU32, is_owner: bool) -> io::Result<()> {
+    if is_owner {
+        format.store(FRAME_FORMAT, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    let format = format.load(Ordering::Relaxed);
+    if format != FRAME_FORMAT {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("queue uses frame format {format}, but we only support {FRAME_FORMAT}"),
+        ));
+    }
+    Ok(())
+}
+
+/// # Safety
+/// There must be a committed frame at `offset`.
+unsafe fn read_frame_header(left: &Mmap, offset: Offset) -> u64 {
+    // SAFETY: guaranteed by the caller, we keep offsets in-bounds.
+    let bytes = unsafe {
+        left.as_ptr()
+            .add(offset as usize)
+            .cast::<[u8; FRAME_HEADER_SIZE]>()
+            .read()
+    };
+    u64::from_le_bytes(bytes)
+}
+
+/// # Safety
+/// Space for the frame must be reserved, and nobody may read it until it's committed.
+unsafe fn write_frame_header(left: &Mmap, offset: Offset, header: u64) {
+    // SAFETY: guaranteed by the caller, we keep offsets in-bounds.
+    unsafe {
+        left.as_ptr()
+            .add(offset as usize)
+            .cast::<[u8; FRAME_HEADER_SIZE]>()
+            .write(header.to_le_bytes())
+    };
+}
+
+/// A message frame that was read from the queue.
+pub(crate) struct Frame<'a> {
+    pub(crate) meta: Option<MessageMeta>,
+    pub(crate) checksum: Option<u32>,
+    pub(crate) message: &'a [u8],
+    /// Offset of the next frame.
+    pub(crate) end: Offset,
+}
+
+/// Reads a frame at `offset` (not a padding one). Returns `None` if its header is corrupted, i.e.
+/// the frame doesn't end before `end`.
+///
+/// # Safety
+/// Everything between `offset` and `end` must be committed, and must not be consumed while the
+/// returned frame is alive.
+unsafe fn read_frame(left: &Mmap, offset: Offset, end: Offset) -> Option<Frame<'_>> {
+    let available = (end - offset) as usize;
+    if available < FRAME_HEADER_SIZE {
+        return None;
+    }
+    // SAFETY: guaranteed by the caller.
+    let header = unsafe { read_frame_header(left, offset) };
+    let prefix = match header & META {
+        0 => FRAME_HEADER_SIZE,
+        _ => FRAME_HEADER_SIZE + META_SIZE,
+    };
+    let size = usize::try_from(header & SIZE_MASK).ok()?;
+    if header & PADDING != 0 || size > available.checked_sub(prefix)? {
+        return None;
+    }
+
+    // SAFETY: guaranteed by the caller, the frame is in bounds as checked above.
+    unsafe {
+        let data_ptr = left.as_ptr().add(offset as usize + FRAME_HEADER_SIZE);
+        let (meta, checksum) = if header & META != 0 {
+            let bytes = data_ptr.cast::<[u8; META_SIZE]>().read();
+            let checksum = (header & CHECKSUM != 0).then(|| meta::read_checksum(&bytes));
+            (Some(MessageMeta::from_bytes(bytes)), checksum)
+        } else {
+            (None, None)
+        };
+        Some(Frame {
+            meta,
+            checksum,
+            message: slice::from_raw_parts(left.as_ptr().add(offset as usize + prefix), size),
+            end: next_offset(offset, prefix - FRAME_HEADER_SIZE + size),
+        })
+    }
+}
+
+/// Writes a whole frame with `buf` as a message at `offset`.
+///
+/// # Safety
+/// Space for the frame must be reserved, and nobody may read it until it's committed.
+unsafe fn write_message(left: &Mmap, offset: Offset, buf: &[u8]) {
+    // SAFETY: guaranteed by the caller, we keep offsets in-bounds.
+    unsafe {
+        let data_ptr = left.as_ptr().add(offset as usize + FRAME_HEADER_SIZE);
+        std::ptr::copy_nonoverlapping(buf.as_ptr(), data_ptr, buf.len());
+        write_frame_header(left, offset, buf.len() as u64);
+    }
+}
+
+/// An iterator over received messages, see [`MemeQueue::recv_batch()`].
+pub struct Messages<'a> {
+    left: &'a Mmap,
+    offset: Offset,
+    end: Offset,
+    remaining: usize,
+    expected_seq: u64,
+    corrupted: Option<Corrupted>,
+}
+
+impl<'a> Iterator for Messages<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.offset >= self.end || self.remaining == 0 || self.corrupted.is_some() {
+            return None;
+        }
+
+        // SAFETY: everything before `end` is committed, and the caller of `.recv_batch()` is
+        // holding left lock until the iterator is gone.
+        let frame_offset = unsafe { skip_padding(self.left, self.offset, self.end) };
+        if frame_offset == Some(self.end) {
+            // Only padding left by a producer that panicked, it's consumed along with the rest.
+            self.offset = self.end;
+            return None;
+        }
+        // SAFETY: same as above.
+        let frame =
+            frame_offset.and_then(|offset| unsafe { read_frame(self.left, offset, self.end) });
+        let Some(frame) = frame else {
+            self.corrupted = Some(Corrupted::header(self.expected_seq));
+            return None;
+        };
+        if let Err(corrupted) = frame.verify(self.expected_seq) {
+            (self.offset, self.expected_seq) = corrupted.resume(self.offset, &frame);
+            self.corrupted = Some(corrupted);
+            return None;
+        }
+        self.offset = frame.end;
+        self.expected_seq = frame.next_seq().unwrap_or(self.expected_seq);
+        self.remaining -= 1;
+        Some(frame.message)
+    }
+}
+
+/// A received message that's not consumed yet. Derefs to the message.
+///
+/// Dropping the guard without calling [`RecvGuard::commit()`] leaves the message in the queue.
+pub struct RecvGuard<'a, H, C: Control<H>> {
+    queue: &'a MemeQueue<H, C>,
+    lock: C::LockGuard<'a>,
+    frame: Frame<'a>,
+}
+
+impl<H, C: Control<H>> RecvGuard<'_, H, C> {
+    /// Returns the message without consuming it.
+    pub fn peek(&self) -> &[u8] {
+        self.frame.message
+    }
+
+    /// Returns the metadata of the message, if it was sent with it.
+    pub fn meta(&self) -> Option<MessageMeta> {
+        self.frame.meta
+    }
+
+    /// Consumes the message.
+    pub fn commit(self) -> io::Result<()> {
+        let expected_seq = self.frame.next_seq();
+        self.queue.consume(self.lock, self.frame.end, expected_seq)
+    }
+
+    /// Leaves the message for the next reader. Same as just dropping the guard.
+    pub fn abort(self) {}
+}
+
+impl<H, C: Control<H>> Deref for RecvGuard<'_, H, C> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.frame.message
+    }
+}
+
+/// A message being written directly into the queue. Derefs to the reserved space.
+///
+/// Dropping the guard without calling [`SendGuard::commit()`] aborts the message.
+pub struct SendGuard<'a, H, C: Control<H>> {
+    queue: &'a MemeQueue<H, C>,
+    _lock: C::LockGuard<'a>,
+    right_offset: Offset,
+    len: usize,
+}
+
+impl<H, C: Control<H>> SendGuard<'_, H, C> {
+    /// Sends the message.
+    pub fn commit(self) -> io::Result<()> {
+        self.queue.commit_frame(self.right_offset, self.len)
+    }
+
+    /// Drops the message without sending it. Same as just dropping the guard.
+    pub fn abort(self) {}
+}
+
+impl<H, C: Control<H>> Deref for SendGuard<'_, H, C> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        // SAFETY: space is reserved for us and we're holding right lock.
+        unsafe { slice::from_raw_parts(self.queue.message_ptr(self.right_offset), self.len) }
+    }
+}
+
+impl<H, C: Control<H>> DerefMut for SendGuard<'_, H, C> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        // SAFETY: space is reserved for us and we're holding right lock.
+        unsafe { slice::from_raw_parts_mut(self.queue.message_ptr(self.right_offset), self.len) }
+    }
+}
+
+/// A batch of messages being written, see [`MemeQueue::send_batch()`].
+pub struct SendBatch<'a, H, C> {
+    queue: &'a MemeQueue<H, C>,
+    right_offset: Offset,
+    len: Offset,
+}
+
+impl<H, C: Control<H>> SendBatch<'_, H, C> {
+    /// Adds a message to the batch.
+    pub fn push(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.push_with(|writer| writer.write_all(buf))
+    }
+
+    /// Adds a message to the batch, writing it with a [`MemeWriter`] like [`MemeQueue::send()`].
+    /// If callback fails, the message is not added, but the batch is still usable.
+    pub fn push_with<R, E, F>(&mut self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        let mut writer = MemeWriter {
+            queue: self.queue,
+            total_written: 0,
+            right_offset: self.right_offset + self.len,
+            pending: self.len,
+            deadline: None,
+        };
+        // Space for size and metadata
+        let res = writer
+            .write_all(&[0; MESSAGE_OFFSET])
+            .map_err(E::from)
+            .and_then(|()| cb(&mut writer));
+
+        // Writer could've wrapped offsets around.
+        self.right_offset = writer.right_offset - self.len;
+        if res.is_ok() {
+            let message_size = writer.total_written as usize - MESSAGE_OFFSET;
+            let meta = self.queue.default_meta();
+            self.queue
+                .finish_frame(writer.right_offset, meta, message_size);
+            self.len += writer.total_written;
         }
 
         res
@@ -154,16 +1155,19 @@ impl<H, C: Control<H>> MemeQueue<H, C> {
 
 pub struct MemeWriter<'a, H, C> {
     queue: &'a MemeQueue<H, C>,
-    total_written: u32,
-    right_offset: u32,
+    total_written: Offset,
+    right_offset: Offset,
+    // Bytes written before `right_offset` but not committed yet.
+    pending: Offset,
+    deadline: Option<Instant>,
 }
 
 impl<H, C: Control<H>> Write for MemeWriter<'_, H, C> {
+    #[allow(clippy::io_other_error)] // Kind is spelled out until it can be `StorageFull`.
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let next_total_written = self.total_written as u64 + buf.len() as u64;
-        if next_total_written > u32::MAX as u64
-            || next_total_written > (self.queue.left.size() - mem::size_of::<usize>()) as u64
-        {
+        // Queue size is checked to fit into `Offset` twice, so this can't overflow it.
+        let next_total_written = self.total_written as usize + buf.len();
+        if self.pending as usize + next_total_written > self.queue.left.size() {
             // TODO: maybe Ok(0)?
             return Err(io::Error::new(
                 // TODO: should be `StorageFull`
@@ -206,14 +1210,15 @@ impl<H, C: Control<H>> Write for MemeWriter<'_, H, C> {
                 unsafe {
                     std::ptr::copy_nonoverlapping(buf_part.as_ptr(), right_ptr, buf_part.len());
                 }
-                self.total_written += buf_part.len() as u32;
+                self.total_written += buf_part.len() as Offset;
                 return Ok(buf_part.len());
             } else if left_offset as usize >= left.size() {
                 let _left_guard = control.lock(Side::Left);
                 let left_offset = control.load_offset(Side::Left);
-                let new_left_offset = left_offset - left.size() as u32;
-                let new_right_offset = right_offset - self.total_written - left.size() as u32;
-                control.fix_offsets(new_left_offset, new_right_offset);
+                let new_left_offset = left_offset - left.size() as Offset;
+                let new_right_offset = right_offset - self.total_written - left.size() as Offset;
+                // Pending bytes are not committed yet, so committed offset is before them.
+                control.fix_offsets(new_left_offset, new_right_offset - self.pending);
                 self.right_offset = new_right_offset;
             } else {
                 // Error safety: there're two cases.
@@ -221,7 +1226,7 @@ impl<H, C: Control<H>> Write for MemeWriter<'_, H, C> {
                 // 2. If caller hides the error, we will commit everything we've written.
                 //    Size is calculated by `.total_written`, which is synchronized with actual
                 //    bytes written, so it's ok, although the message will obviously be malformed.
-                control.wait(Side::Left, left_offset)?;
+                control.wait(Side::Left, left_offset, self.deadline)?;
             }
         }
     }
@@ -230,3 +1235,277 @@ impl<H, C: Control<H>> Write for MemeWriter<'_, H, C> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write as _},
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        check_offsets_fit,
+        control::Side,
+        handshake::NamedFileHandshakeResult,
+        testing::{pair, recv, send, try_recv, TempPath},
+        Control, Offset,
+    };
+
+    #[test]
+    fn aligned_messages_wrap_around() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        // Odd sizes need padding before every message, and the total is many times the ring.
+        for idx in 0..500_usize {
+            let len = 1 + idx * 37 % 300;
+            let mut guard = sender.send_reserve_aligned(len, 64).unwrap();
+            guard.fill(idx as u8);
+            guard.commit().unwrap();
+
+            let guard = receiver.recv_guard().unwrap();
+            assert_eq!(guard.as_ptr() as usize % 64, 0);
+            assert_eq!(*guard, vec![idx as u8; len]);
+            guard.commit().unwrap();
+        }
+    }
+
+    #[test]
+    fn batches_skip_padding() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        for round in 0..100_usize {
+            for idx in 0..3 {
+                let mut guard = sender.send_reserve_aligned(round + idx + 1, 16).unwrap();
+                guard.fill(idx as u8);
+                guard.commit().unwrap();
+            }
+            let messages = receiver
+                .recv_batch(usize::MAX, |messages| {
+                    io::Result::Ok(messages.map(<[u8]>::to_vec).collect::<Vec<_>>())
+                })
+                .unwrap();
+            let expected: Vec<_> = (0..3).map(|idx| vec![idx as u8; round + idx + 1]).collect();
+            assert_eq!(messages, expected);
+        }
+    }
+
+    #[test]
+    fn close_drains_then_reports_eof() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        send(&sender, b"one").unwrap();
+        send(&sender, b"two").unwrap();
+        sender.close().unwrap();
+
+        assert_eq!(recv(&receiver).unwrap(), b"one");
+        assert_eq!(try_recv(&receiver).unwrap(), b"two");
+        let err = recv(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = try_recv(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[cfg(all(feature = "async", feature = "handshake_uds_memfd"))]
+    #[tokio::test]
+    async fn async_round_trip() {
+        use crate::testing::eventfd_pair;
+
+        let path = TempPath::new();
+        let (sender, receiver) = eventfd_pair(&path, 4096);
+        // Many times the ring, so both sides have to park.
+        let messages: Vec<Vec<u8>> = (0..2000_u32)
+            .map(|idx| idx.to_le_bytes().repeat(idx as usize % 50))
+            .collect();
+
+        let send = async {
+            for message in &messages {
+                sender.send_async(message).await.unwrap();
+            }
+            sender.close().unwrap();
+        };
+        let recv = async {
+            let mut received = Vec::new();
+            loop {
+                match receiver
+                    .recv_async(|buf| io::Result::Ok(buf.to_vec()))
+                    .await
+                {
+                    Ok(message) => received.push(message),
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return received,
+                    Err(err) => panic!("failed to receive: {err}"),
+                }
+            }
+        };
+        let ((), received) = tokio::join!(send, recv);
+        assert_eq!(received, messages);
+    }
+
+    #[test]
+    fn empty_queue_is_not_eof() {
+        let path = TempPath::new();
+        let (_sender, receiver) = pair(&path, 4096);
+
+        let err = try_recv(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn timeouts_expire() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        let start = Instant::now();
+        let err = receiver
+            .recv_timeout(Duration::from_millis(50), |_buf| io::Result::Ok(()))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        let err = receiver
+            .recv_deadline(Instant::now(), |_buf| io::Result::Ok(()))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        send(&sender, &[1; 3000]).unwrap();
+        let err = sender
+            .send_timeout(Duration::from_millis(50), |writer| {
+                writer.write_all(&[2; 3000])
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // Message that timed out is not sent.
+        assert_eq!(recv(&receiver).unwrap(), [1; 3000]);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        sender
+            .send_deadline(deadline, |writer| writer.write_all(&[3; 3000]))
+            .unwrap();
+        let message = receiver
+            .recv_deadline(deadline, |buf| io::Result::Ok(buf.to_vec()))
+            .unwrap();
+        assert_eq!(message, [3; 3000]);
+    }
+
+    #[test]
+    fn reserved_messages_are_sent_on_commit() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        let mut guard = sender.send_reserve(5).unwrap();
+        guard.copy_from_slice(b"first");
+        guard.abort();
+        let err = try_recv(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        // Many times the ring, so reservations wrap around.
+        for idx in 0..500_usize {
+            let len = 1 + idx * 37 % 300;
+            let mut guard = sender.send_reserve(len).unwrap();
+            guard.fill(idx as u8);
+            guard.commit().unwrap();
+            assert_eq!(recv(&receiver).unwrap(), vec![idx as u8; len]);
+        }
+    }
+
+    #[test]
+    fn aborted_guard_leaves_message() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        send(&sender, b"first").unwrap();
+        send(&sender, b"second").unwrap();
+        let guard = receiver.recv_guard().unwrap();
+        assert_eq!(guard.peek(), b"first");
+        guard.abort();
+        let guard = receiver.recv_guard().unwrap();
+        assert_eq!(&*guard, b"first");
+        guard.commit().unwrap();
+        assert_eq!(recv(&receiver).unwrap(), b"second");
+    }
+
+    #[test]
+    fn batch_consumes_only_yielded_messages() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        for idx in 0..10_u8 {
+            send(&sender, &[idx; 10]).unwrap();
+        }
+        let recv_batch = |max, take| {
+            receiver
+                .recv_batch(max, |messages| {
+                    io::Result::Ok(messages.take(take).map(<[u8]>::to_vec).collect::<Vec<_>>())
+                })
+                .unwrap()
+        };
+        let first: Vec<_> = (0..4).map(|idx| vec![idx; 10]).collect();
+        assert_eq!(recv_batch(4, usize::MAX), first);
+        let second: Vec<_> = (4..6).map(|idx| vec![idx; 10]).collect();
+        assert_eq!(recv_batch(usize::MAX, 2), second);
+        let rest: Vec<_> = (6..10).map(|idx| vec![idx; 10]).collect();
+        assert_eq!(recv_batch(usize::MAX, usize::MAX), rest);
+        let err = try_recv(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn batch_is_sent_all_at_once() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        let err = sender
+            .send_batch(|batch| {
+                batch.push(b"lost")?;
+                Err::<(), _>(io::Error::other("changed my mind"))
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "changed my mind");
+        let err = try_recv(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        sender
+            .send_batch(|batch| {
+                batch.push(b"one")?;
+                batch.push_with(|writer| writer.write_all(b"two"))?;
+                // Nothing is visible until the batch is done.
+                let err = try_recv(&receiver).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+                io::Result::Ok(())
+            })
+            .unwrap();
+        assert_eq!(recv(&receiver).unwrap(), b"one");
+        assert_eq!(recv(&receiver).unwrap(), b"two");
+    }
+
+    #[test]
+    fn offsets_are_moved_back_by_ring_size() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        // Many times twice the ring, so offsets have to move back.
+        for idx in 0..2000_u32 {
+            let message = idx.to_le_bytes().repeat(idx as usize % 30);
+            send(&sender, &message).unwrap();
+            assert_eq!(recv(&receiver).unwrap(), message);
+
+            let load_offset =
+                |side| Control::<NamedFileHandshakeResult>::load_offset(&receiver.control, side);
+            let (left_offset, right_offset) = (load_offset(Side::Left), load_offset(Side::Right));
+            assert!(left_offset <= right_offset && right_offset <= 2 * 4096);
+        }
+    }
+
+    #[test]
+    fn large_queues_need_offsets64() {
+        let queue_size = 8 << 30;
+        let res = check_offsets_fit(queue_size);
+        if cfg!(feature = "offsets64") {
+            res.unwrap();
+        } else {
+            assert_eq!(res.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        }
+        let err = check_offsets_fit(Offset::MAX as usize / 2 + 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
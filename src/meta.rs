@@ -0,0 +1,236 @@
+use std::{
+    io,
+    sync::{atomic::Ordering, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::{Control, MemeQueue, MemeWriter};
+
+/// Size of the metadata block in a frame.
+pub(crate) const META_SIZE: usize = 24;
+
+/// Metadata of a message sent with [`MemeQueue::send_tagged()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MessageMeta {
+    /// Chosen by the sender, e.g. to tell apart different message types on one queue.
+    pub tag: u32,
+    /// Number of messages with metadata sent to this queue before this one.
+    pub seq: u64,
+    /// Raw [`quanta::Clock`] reading taken when the message was sent. Raw readings are the same
+    /// in every process on the machine.
+    pub sent_at: u64,
+}
+
+impl MessageMeta {
+    /// Time since the message was sent.
+    pub fn elapsed(&self) -> Duration {
+        let clock = clock();
+        clock.delta(self.sent_at, clock.raw())
+    }
+
+    // Layout: tag and message checksum (zero if the frame has none) as little-endian `u32`s,
+    // then `seq` and `sent_at` as little-endian `u64`s.
+    pub(crate) fn to_bytes(self, checksum: u32) -> [u8; META_SIZE] {
+        let mut bytes = [0; META_SIZE];
+        bytes[0..4].copy_from_slice(&self.tag.to_le_bytes());
+        bytes[4..8].copy_from_slice(&checksum.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.seq.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.sent_at.to_le_bytes());
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; META_SIZE]) -> Self {
+        Self {
+            tag: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            seq: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            sent_at: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+        }
+    }
+}
+
+pub(crate) fn read_checksum(bytes: &[u8; META_SIZE]) -> u32 {
+    u32::from_le_bytes(bytes[4..8].try_into().unwrap())
+}
+
+/// Calibrating a clock is expensive, so we do it once per process.
+fn clock() -> &'static quanta::Clock {
+    static CLOCK: OnceLock<quanta::Clock> = OnceLock::new();
+    CLOCK.get_or_init(quanta::Clock::new)
+}
+
+impl<H, C: Control<H>> MemeQueue<H, C> {
+    /// Like [`MemeQueue::send()`], but also records [`MessageMeta`] with the given `tag` in the
+    /// frame. Sequence number and timestamp are assigned when the message is committed, so
+    /// messages that failed to send don't leave gaps.
+    pub fn send_tagged<R, E, F>(&self, tag: u32, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.send_frame_until(Some(tag), None, cb)
+    }
+
+    /// Like [`MemeQueue::send_tagged()`], but writes fail with [`io::ErrorKind::TimedOut`] if
+    /// there's still no space after `timeout`. If callback propagates the error, nothing is sent.
+    pub fn send_tagged_timeout<R, E, F>(&self, tag: u32, timeout: Duration, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.send_frame_until(Some(tag), Instant::now().checked_add(timeout), cb)
+    }
+
+    /// Like [`MemeQueue::send_tagged()`], but writes fail with [`io::ErrorKind::TimedOut`] if
+    /// there's still no space by `deadline`. If callback propagates the error, nothing is sent.
+    pub fn send_tagged_deadline<R, E, F>(&self, tag: u32, deadline: Instant, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.send_frame_until(Some(tag), Some(deadline), cb)
+    }
+
+    /// Like [`MemeQueue::recv()`], but also passes [`MessageMeta`] to the callback, if the
+    /// message has it.
+    pub fn recv_meta<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(Option<MessageMeta>, &[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.recv_until(None, cb)
+    }
+
+    /// Like [`MemeQueue::recv_meta()`], but fails with [`io::ErrorKind::TimedOut`] if there's no
+    /// message after `timeout`.
+    pub fn recv_meta_timeout<R, E, F>(&self, timeout: Duration, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(Option<MessageMeta>, &[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.recv_until(Instant::now().checked_add(timeout), cb)
+    }
+
+    /// Like [`MemeQueue::recv_meta()`], but fails with [`io::ErrorKind::TimedOut`] if there's no
+    /// message by `deadline`.
+    pub fn recv_meta_deadline<R, E, F>(&self, deadline: Instant, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(Option<MessageMeta>, &[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.recv_until(Some(deadline), cb)
+    }
+
+    /// Like [`MemeQueue::recv_meta()`], but fails with [`io::ErrorKind::WouldBlock`] instead of
+    /// waiting if the queue is empty and not closed.
+    pub fn try_recv_meta<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(Option<MessageMeta>, &[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        match self.recv_now(cb) {
+            Ok(res) => res,
+            Err((_cb, right_offset)) => {
+                self.check_eof(right_offset)?;
+                Err(io::Error::from(io::ErrorKind::WouldBlock).into())
+            }
+        }
+    }
+
+    /// Metadata for the next message sent without a tag: none, unless `verify` feature is
+    /// enabled. Right lock must be held.
+    pub(crate) fn default_meta(&self) -> Option<MessageMeta> {
+        cfg!(feature = "verify").then(|| self.next_meta(0))
+    }
+
+    /// Metadata for the next message. Right lock must be held.
+    pub(crate) fn next_meta(&self, tag: u32) -> MessageMeta {
+        let next_seq = self.control.next_seq();
+        let seq = next_seq.load(Ordering::Relaxed);
+        next_seq.store(seq + 1, Ordering::Relaxed);
+        MessageMeta {
+            tag,
+            seq,
+            sent_at: clock().raw(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write as _},
+        time::{Duration, Instant},
+    };
+
+    use super::MessageMeta;
+    use crate::testing::{pair, TempPath, TestQueue};
+
+    fn recv_meta(queue: &TestQueue) -> io::Result<(Option<MessageMeta>, Vec<u8>)> {
+        queue.recv_meta_deadline(Instant::now() + Duration::from_secs(5), |meta, buf| {
+            Ok((meta, buf.to_vec()))
+        })
+    }
+
+    #[test]
+    fn metadata_is_sent_with_tagged_messages() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        let before = super::clock().raw();
+        sender
+            .send_tagged(7, |writer| writer.write_all(b"first"))
+            .unwrap();
+        sender
+            .send_tagged_timeout(8, Duration::from_secs(5), |writer| {
+                writer.write_all(b"second")
+            })
+            .unwrap();
+
+        let (meta, message) = recv_meta(&receiver).unwrap();
+        let meta = meta.unwrap();
+        assert_eq!(
+            (meta.tag, meta.seq, message.as_slice()),
+            (7, 0, &b"first"[..])
+        );
+        assert!(meta.sent_at >= before);
+        assert!(meta.elapsed() < Duration::from_secs(5));
+        let (meta, message) = recv_meta(&receiver).unwrap();
+        let meta = meta.unwrap();
+        assert_eq!(
+            (meta.tag, meta.seq, message.as_slice()),
+            (8, 1, &b"second"[..])
+        );
+    }
+
+    #[test]
+    fn tagged_deadlines_time_out() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        let err = receiver
+            .recv_meta_deadline(Instant::now(), |_meta, _buf| io::Result::Ok(()))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        sender
+            .send_tagged(1, |writer| writer.write_all(&[1; 3000]))
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let err = sender
+            .send_tagged_deadline(2, deadline, |writer| writer.write_all(&[2; 3000]))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(recv_meta(&receiver).unwrap().0.unwrap().seq, 0);
+
+        // Failed message doesn't take a sequence number.
+        sender
+            .send_tagged(3, |writer| writer.write_all(b"after"))
+            .unwrap();
+        let (meta, message) = recv_meta(&receiver).unwrap();
+        let meta = meta.unwrap();
+        assert_eq!(
+            (meta.tag, meta.seq, message.as_slice()),
+            (3, 1, &b"after"[..])
+        );
+    }
+}
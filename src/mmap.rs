@@ -26,6 +26,9 @@ pub struct Mmap {
 
 // SAFETY: todo
 unsafe impl Send for Mmap {}
+// SAFETY: we only hand out raw pointers, and the memory is shared with other processes anyway,
+// so all accesses must already be synchronized.
+unsafe impl Sync for Mmap {}
 
 impl Mmap {
     pub(crate) fn as_ptr(&self) -> *mut u8 {
@@ -54,17 +57,26 @@ pub(crate) struct QueueMmaps {
 }
 
 impl QueueMmaps {
+    /// Maps a queue with the header at `header_offset`, followed by the ring.
+    ///
     /// # Safety
-    /// `fd` must point to a file which has enough space for `PAGE_SIZE + queue_size` bytes.
+    /// `fd` must point to a file which has enough space for `header_offset + PAGE_SIZE + queue_size`
+    /// bytes. `header_offset` must be a multiple of page size.
     #[rustfmt::skip]
-    pub(crate) unsafe fn from_fd<F: AsRawFd>(fd: &F, queue_size: usize) -> io::Result<Self> {
+    pub(crate) unsafe fn from_fd<F: AsRawFd>(
+        fd: &F,
+        header_offset: usize,
+        queue_size: usize,
+    ) -> io::Result<Self> {
         use libc::{
-            mmap, munmap, MAP_ANONYMOUS, MAP_FAILED, MAP_FIXED, MAP_SHARED, PROT_READ, PROT_WRITE,
+            mmap, munmap, MAP_ANONYMOUS, MAP_FAILED, MAP_FIXED, MAP_NORESERVE, MAP_PRIVATE,
+            MAP_SHARED, PROT_NONE, PROT_READ, PROT_WRITE,
         };
 
         let fd = fd.as_raw_fd();
         let page_size = get_page_size();
-        let offset = i64::try_from(page_size).expect("page size must fit into i64");
+        let header_offset = i64::try_from(header_offset).expect("header offset must fit into i64");
+        let offset = header_offset + i64::try_from(page_size).expect("page size must fit into i64");
 
         if queue_size % page_size != 0 {
             return Err(io::Error::new(
@@ -73,12 +85,13 @@ impl QueueMmaps {
             ));
         }
 
+        // Only reserves address space, so it doesn't count towards memory limits for large queues.
         // SAFETY: a valid anonymous mapping.
         let big = unsafe {
             mmap(
                 ptr::null_mut(), queue_size * 2,
-                PROT_READ | PROT_WRITE, MAP_SHARED | MAP_ANONYMOUS,
-                0, 0,
+                PROT_NONE, MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE,
+                -1, 0,
             )
         };
         if big == MAP_FAILED {
@@ -128,7 +141,7 @@ impl QueueMmaps {
             mmap(
                 ptr::null_mut(), page_size,
                 PROT_READ | PROT_WRITE, MAP_SHARED,
-                fd, 0,
+                fd, header_offset,
             )
         };
         if header == MAP_FAILED {
@@ -0,0 +1,275 @@
+use std::{
+    fs::File,
+    mem,
+    os::unix::fs::FileExt as _,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+use crate::{handshake::HandshakeError, mmap::get_page_size};
+
+/// Marks an initialized header page, spells "meme".
+const MAGIC: u32 = u32::from_le_bytes(*b"meme");
+/// Bumped whenever the layout of any header page changes.
+const VERSION: u32 = 4;
+
+/// Set in [`Preamble`] layout flags if offsets are 64-bit, i.e. with `offsets64` feature.
+const OFFSETS64: u32 = 1 << 0;
+
+/// Layout flags of this build. Header pages are laid out differently depending on them.
+const LAYOUT_FLAGS: u32 = if cfg!(feature = "offsets64") {
+    OFFSETS64
+} else {
+    0
+};
+
+/// What kind of header follows the preamble. Peers must use the same one.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ControlKind {
+    ShmemFutex = 1,
+    EventFd = 2,
+    Broadcast = 3,
+}
+
+impl ControlKind {
+    fn name(raw: u32) -> Option<&'static str> {
+        Some(match raw {
+            1 => "`ShmemFutexControl`",
+            2 => "`EventFdControl`",
+            3 => "broadcast",
+            _ => return None,
+        })
+    }
+}
+
+/// Start of every header page. Owner fills it in, and everyone else checks it, so that a build
+/// with a different layout fails to connect instead of misinterpreting the header.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct Preamble {
+    magic: AtomicU32,
+    version: AtomicU32,
+    page_size: AtomicU32,
+    control_kind: AtomicU32,
+    layout_flags: AtomicU32,
+    /// Always zero, keeps `ring_size` aligned.
+    reserved: AtomicU32,
+    ring_size: AtomicU64,
+}
+
+/// Plain values of [`Preamble`] fields, e.g. read from a file.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Fields {
+    magic: u32,
+    version: u32,
+    page_size: u32,
+    control_kind: u32,
+    layout_flags: u32,
+    reserved: u32,
+    ring_size: u64,
+}
+
+const _: () = assert!(mem::size_of::<Preamble>() == mem::size_of::<Fields>());
+
+impl Preamble {
+    /// Fills in everything but the magic for a new ring of `ring_size` bytes.
+    pub(crate) fn init(&self, kind: ControlKind, ring_size: usize) {
+        self.version.store(VERSION, Ordering::Relaxed);
+        self.page_size
+            .store(get_page_size() as u32, Ordering::Relaxed);
+        self.control_kind.store(kind as u32, Ordering::Relaxed);
+        self.layout_flags.store(LAYOUT_FLAGS, Ordering::Relaxed);
+        self.ring_size.store(ring_size as u64, Ordering::Relaxed);
+    }
+
+    /// Marks the header as set up. Must come last, so that a crash in the middle of setup doesn't
+    /// leave a valid-looking header.
+    pub(crate) fn publish(&self) {
+        self.magic.store(MAGIC, Ordering::Release);
+    }
+
+    /// Fails with [`HandshakeError::ProtocolMismatch`] if the header wasn't set up for a ring of
+    /// `ring_size` bytes with `kind` of control.
+    pub(crate) fn check(&self, kind: ControlKind, ring_size: usize) -> Result<(), HandshakeError> {
+        let fields = self.load();
+        fields.check()?;
+        if fields.control_kind != kind as u32 {
+            return Err(mismatch(format!(
+                "queue uses {}, but we use {}",
+                ControlKind::name(fields.control_kind).unwrap_or("unknown"),
+                ControlKind::name(kind as u32).unwrap_or("unknown"),
+            )));
+        }
+        if fields.ring_size != ring_size as u64 {
+            return Err(mismatch(format!(
+                "queue ring has {} bytes, but we expected {ring_size}",
+                fields.ring_size,
+            )));
+        }
+        Ok(())
+    }
+
+    fn load(&self) -> Fields {
+        Fields {
+            magic: self.magic.load(Ordering::Acquire),
+            version: self.version.load(Ordering::Relaxed),
+            page_size: self.page_size.load(Ordering::Relaxed),
+            control_kind: self.control_kind.load(Ordering::Relaxed),
+            layout_flags: self.layout_flags.load(Ordering::Relaxed),
+            reserved: self.reserved.load(Ordering::Relaxed),
+            ring_size: self.ring_size.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Fields {
+    /// Checks everything that doesn't depend on how the header page is used.
+    fn check(&self) -> Result<(), HandshakeError> {
+        if self.magic != MAGIC {
+            return Err(mismatch(format!(
+                "not a memequeue header (magic is {:#010x})",
+                self.magic,
+            )));
+        }
+        if self.version != VERSION {
+            return Err(mismatch(format!(
+                "queue uses header version {}, but we only support {VERSION}",
+                self.version,
+            )));
+        }
+        let page_size = get_page_size();
+        if self.page_size as usize != page_size {
+            return Err(mismatch(format!(
+                "queue was created with page size {}, but ours is {page_size}",
+                self.page_size,
+            )));
+        }
+        if ControlKind::name(self.control_kind).is_none() {
+            return Err(mismatch(format!(
+                "queue uses unknown control kind {}",
+                self.control_kind,
+            )));
+        }
+        if (self.layout_flags ^ LAYOUT_FLAGS) & OFFSETS64 != 0 {
+            let with = |flags| {
+                if flags & OFFSETS64 != 0 {
+                    "with"
+                } else {
+                    "without"
+                }
+            };
+            return Err(mismatch(format!(
+                "queue was created {} `offsets64` feature, but we are built {} it",
+                with(self.layout_flags),
+                with(LAYOUT_FLAGS),
+            )));
+        }
+        if self.layout_flags != LAYOUT_FLAGS {
+            return Err(mismatch(format!(
+                "queue uses unknown layout flags {:#x}",
+                self.layout_flags & !LAYOUT_FLAGS,
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Reads the preamble of the first header page of a queue of `queue_size` bytes in `file` and
+/// checks it. Used by handshakes when connecting to an existing queue, control kind and exact
+/// ring size are checked later by the control.
+pub(crate) fn check_file(file: &File, queue_size: usize) -> Result<(), HandshakeError> {
+    let mut buf = [0; mem::size_of::<Fields>()];
+    file.read_exact_at(&mut buf, 0)?;
+    // SAFETY: `Fields` has no padding, any bytes are valid for it, and the buffer has its size.
+    let fields = unsafe { buf.as_ptr().cast::<Fields>().read_unaligned() };
+    fields.check()?;
+    if fields.ring_size > queue_size as u64 {
+        return Err(mismatch(format!(
+            "queue ring has {} bytes, but the whole queue only has {queue_size}",
+            fields.ring_size,
+        )));
+    }
+    Ok(())
+}
+
+fn mismatch(message: String) -> HandshakeError {
+    HandshakeError::ProtocolMismatch(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, mem, os::unix::fs::FileExt as _};
+
+    use super::{ControlKind, Fields, LAYOUT_FLAGS, MAGIC, OFFSETS64, VERSION};
+    use crate::{
+        handshake::{named_file, HandshakeError},
+        mmap::get_page_size,
+        testing::{open, TempPath},
+        MemeQueue, ShmemFutexControl, ShmemFutexControlConfig,
+    };
+
+    /// Sets up a queue, overwrites the preamble field at `field_offset` with `value`, and returns
+    /// why connecting to it as a `ShmemFutexControl` queue failed.
+    fn connect_with(field_offset: usize, value: u32) -> HandshakeError {
+        let path = TempPath::new();
+        let _owner = open(&path, 4096, ShmemFutexControlConfig::default());
+        let file = OpenOptions::new().write(true).open(&*path).unwrap();
+        file.write_all_at(&value.to_le_bytes(), field_offset as u64)
+            .unwrap();
+
+        // SAFETY: test files are only used through queues.
+        let res = unsafe { named_file(&*path, 4096) }.and_then(|handshake_result| {
+            MemeQueue::<_, ShmemFutexControl>::new(handshake_result).map_err(|err| {
+                // Control reports handshake errors as `io::Error`s.
+                *err.into_inner()
+                    .unwrap()
+                    .downcast::<HandshakeError>()
+                    .unwrap()
+            })
+        });
+        let Err(err) = res else {
+            panic!("connected to a queue with a broken preamble");
+        };
+        err
+    }
+
+    #[test]
+    fn every_field_is_checked() {
+        for (field_offset, value) in [
+            (mem::offset_of!(Fields, magic), !MAGIC),
+            (mem::offset_of!(Fields, version), VERSION + 1),
+            (
+                mem::offset_of!(Fields, page_size),
+                2 * get_page_size() as u32,
+            ),
+            (mem::offset_of!(Fields, control_kind), 42),
+            (
+                mem::offset_of!(Fields, layout_flags),
+                LAYOUT_FLAGS ^ OFFSETS64,
+            ),
+            (
+                mem::offset_of!(Fields, layout_flags),
+                LAYOUT_FLAGS | 1 << 31,
+            ),
+        ] {
+            let err = connect_with(field_offset, value);
+            assert!(
+                matches!(err, HandshakeError::ProtocolMismatch(_)),
+                "field at {field_offset} set to {value}: {err}",
+            );
+        }
+    }
+
+    #[test]
+    fn eventfd_queue_is_not_shmem_futex_queue() {
+        let err = connect_with(
+            mem::offset_of!(Fields, control_kind),
+            ControlKind::EventFd as u32,
+        );
+        let HandshakeError::ProtocolMismatch(message) = err else {
+            panic!("unexpected error: {err}");
+        };
+        assert!(message.contains("`EventFdControl`"), "{message}");
+    }
+}
@@ -0,0 +1,407 @@
+use std::{
+    io, slice,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    control::{is_alive, our_pid, time_left, Side},
+    handshake::HandshakeResult,
+    Control, MemeQueue, MessageMeta, Offset,
+};
+
+/// One of possibly many concurrent senders of a [`MemeQueue`]. Can be cloned to get more
+/// producers in the same process.
+///
+/// Right lock is only held to reserve space and to publish written messages, never while writing
+/// or waiting. Messages are published in reservation order, so a slow producer only delays
+/// publishing of messages reserved after its own, not writing them. If it dies before finishing,
+/// its reservation is published as padding by the next producer that publishes, or by a receiver
+/// that waits for it.
+///
+/// Producers can't be mixed with a [`MemeSender`](crate::MemeSender) on the same queue.
+/// Multiple producer processes require a handshake that allows more than two peers, such as
+/// [`named_file()`](crate::handshake::named_file) or `uds_memfd_listener()`.
+pub struct MemeProducer<H, C: Control<H>> {
+    queue: Arc<MemeQueue<H, C>>,
+}
+
+impl<H: HandshakeResult, C: Control<H>> MemeProducer<H, C> {
+    pub fn new(handshake_result: H) -> io::Result<Self>
+    where
+        C::Config: Default,
+    {
+        Self::from_queue(Arc::new(MemeQueue::new(handshake_result)?))
+    }
+
+    pub fn with_config(handshake_result: H, config: C::Config) -> io::Result<Self> {
+        Self::from_queue(Arc::new(MemeQueue::with_config(handshake_result, config)?))
+    }
+}
+
+impl<H, C: Control<H>> MemeProducer<H, C> {
+    pub(crate) fn from_queue(queue: Arc<MemeQueue<H, C>>) -> io::Result<Self> {
+        queue.control.attach_shared(Side::Right)?;
+        Ok(Self { queue })
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> &crate::stats::Stats {
+        self.queue.stats()
+    }
+
+    /// Sends a whole message, waiting for space if needed.
+    pub fn send(&self, buf: &[u8]) -> io::Result<()> {
+        self.send_with_until(buf.len(), None, |dst| dst.copy_from_slice(buf))
+    }
+
+    /// Like [`MemeProducer::send()`], but fails with [`io::ErrorKind::TimedOut`] if there's still
+    /// no space after `timeout`.
+    pub fn send_timeout(&self, buf: &[u8], timeout: Duration) -> io::Result<()> {
+        let deadline = Instant::now().checked_add(timeout);
+        self.send_with_until(buf.len(), deadline, |dst| dst.copy_from_slice(buf))
+    }
+
+    /// Like [`MemeProducer::send()`], but fails with [`io::ErrorKind::TimedOut`] if there's still
+    /// no space by `deadline`.
+    pub fn send_deadline(&self, buf: &[u8], deadline: Instant) -> io::Result<()> {
+        self.send_with_until(buf.len(), Some(deadline), |dst| dst.copy_from_slice(buf))
+    }
+
+    /// Reserves `len` bytes directly in the queue and lets the callback fill them. Other producers
+    /// can reserve and write their messages while the callback runs.
+    ///
+    /// There's no way to abort the message once the space is reserved, so the callback can't fail.
+    /// If it panics, the space is published as padding, and the receiver never sees the message.
+    /// With `verify` feature, the receiver reports its sequence number as a gap.
+    pub fn send_with<R, F>(&self, len: usize, cb: F) -> io::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        self.send_with_until(len, None, cb)
+    }
+
+    /// Sends a whole message without waiting. If there's not enough space for it right now, fails
+    /// with [`io::ErrorKind::WouldBlock`] without writing anything.
+    pub fn try_send(&self, buf: &[u8]) -> io::Result<()> {
+        let frame_size = self.queue.frame_size(buf.len())?;
+        match self.try_reserve(frame_size)? {
+            Ok(reservation) => self.write(reservation, buf.len(), |dst| dst.copy_from_slice(buf)),
+            Err(_) => Err(io::ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn send_with_until<R, F>(&self, len: usize, deadline: Option<Instant>, cb: F) -> io::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let frame_size = self.queue.frame_size(len)?;
+        let control = &self.queue.control;
+
+        let mut waited = false;
+        let reservation = loop {
+            match self.try_reserve(frame_size)? {
+                Ok(reservation) => break reservation,
+                Err(Some(left_offset)) => {
+                    control.wait(Side::Left, left_offset, deadline)?;
+                    waited = true;
+                }
+                // Too many producers are writing right now, they'll be done soon.
+                Err(None) => {
+                    if let Some(deadline) = deadline {
+                        time_left(deadline)?;
+                    }
+                    std::thread::yield_now();
+                }
+            }
+        };
+        // Receiver only wakes one waiter, so pass the notification on to the next producer.
+        if waited {
+            control.notify(Side::Left)?;
+        }
+
+        self.write(reservation, len, cb)
+    }
+
+    /// Reserves space for a frame. If there's no space yet, returns the left offset to wait on, or
+    /// `None` if there are too many reservations.
+    fn try_reserve(&self, frame_size: usize) -> io::Result<Result<Reservation, Option<Offset>>> {
+        let control = &self.queue.control;
+        let producers = control.producers();
+        let _guard = control.lock(Side::Right);
+
+        let mut head = producers.head(control.load_offset(Side::Right));
+        if head.is_none() {
+            // Some of them might belong to dead producers.
+            self.queue.publish_done()?;
+            head = producers.head(control.load_offset(Side::Right));
+        }
+        let Some(mut right_offset) = head else {
+            return Ok(Err(None));
+        };
+        if let Err(left_offset) = self.queue.try_reserve(&mut right_offset, frame_size) {
+            return Ok(Err(Some(left_offset)));
+        }
+        let seq = producers.push(right_offset + frame_size as Offset, our_pid());
+        // Reservations are never aborted, so it's fine to assign sequence numbers right away.
+        let meta = self.queue.default_meta();
+        Ok(Ok(Reservation {
+            seq,
+            right_offset,
+            frame_size,
+            meta,
+        }))
+    }
+
+    /// Writes the reserved frame and publishes every written frame we can.
+    fn write<R, F>(&self, reservation: Reservation, len: usize, cb: F) -> io::Result<R>
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let right_offset = reservation.right_offset;
+        let meta = reservation.meta;
+        let mut publish = Publish {
+            queue: &self.queue,
+            reservation,
+            published: false,
+        };
+        // SAFETY: space is reserved for us, and offsets are only moved by whole ring size, which
+        // maps to the same memory.
+        let dst = unsafe { slice::from_raw_parts_mut(self.queue.message_ptr(right_offset), len) };
+        let res = cb(dst);
+        self.queue.finish_frame(right_offset, meta, len);
+
+        publish.published = true;
+        publish.publish()?;
+        Ok(res)
+    }
+}
+
+/// Space reserved for a frame by [`MemeProducer::try_reserve()`].
+struct Reservation {
+    /// Sequence number in [`Producers`](crate::control::Producers).
+    seq: u32,
+    right_offset: Offset,
+    frame_size: usize,
+    meta: Option<MessageMeta>,
+}
+
+/// Marks a reservation as written and publishes every written frame we can.
+///
+/// Later reservations are only published after this one, so if the frame isn't written because
+/// the callback panicked, it's still published on drop as padding.
+struct Publish<'a, H, C: Control<H>> {
+    queue: &'a MemeQueue<H, C>,
+    reservation: Reservation,
+    published: bool,
+}
+
+impl<H, C: Control<H>> Publish<'_, H, C> {
+    fn publish(&self) -> io::Result<()> {
+        let control = &self.queue.control;
+        let producers = control.producers();
+        producers.mark_done(self.reservation.seq);
+        let _guard = control.lock(Side::Right);
+        self.queue.publish_done()
+    }
+}
+
+impl<H, C: Control<H>> Drop for Publish<'_, H, C> {
+    fn drop(&mut self) {
+        if self.published {
+            return;
+        }
+        let Reservation {
+            right_offset,
+            frame_size,
+            ..
+        } = self.reservation;
+        self.queue.write_padding_header(right_offset, frame_size);
+        // We're already panicking, so there's nothing to do with the error.
+        let _res = self.publish();
+    }
+}
+
+impl<H, C: Control<H>> MemeQueue<H, C> {
+    /// Publishes every written reservation at the start of the queue. Reservations of producers
+    /// that died before writing them are published as padding, otherwise they would hold back
+    /// everything reserved after them forever.
+    ///
+    /// Right lock must be held.
+    fn publish_done(&self) -> io::Result<()> {
+        let producers = self.control.producers();
+        let mut end = None;
+        loop {
+            if let Some(done_end) = producers.pop_done() {
+                end = Some(done_end);
+            }
+            match producers.first_pending() {
+                Some((seq, pid)) if !is_alive(pid) => {
+                    let start = end.unwrap_or_else(|| self.control.load_offset(Side::Right));
+                    self.write_padding_header(start, (producers.end(seq) - start) as usize);
+                    producers.mark_done(seq);
+                }
+                _ => break,
+            }
+        }
+        if let Some(end) = end {
+            self.commit_right(end)?;
+        }
+        Ok(())
+    }
+
+    /// Called by receivers when the right offset is still `right_offset` after a wait, in case
+    /// it's held back by a producer that died in the middle of writing.
+    pub(crate) fn publish_abandoned(&self, right_offset: Offset) -> io::Result<()> {
+        if self.control.load_offset(Side::Right) != right_offset {
+            return Ok(());
+        }
+        match self.control.producers().first_pending() {
+            Some((_seq, pid)) if !is_alive(pid) => {
+                let _guard = self.control.lock(Side::Right);
+                self.publish_done()
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<H, C: Control<H>> MemeProducer<H, C> {
+    /// Creates another producer for the same queue in this process.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Self::from_queue(Arc::clone(&self.queue))
+    }
+}
+
+/// Only panics if there are too many producers already: while we exist, the right side is shared,
+/// so nobody can claim it exclusively. Use [`MemeProducer::try_clone()`] to handle errors.
+impl<H, C: Control<H>> Clone for MemeProducer<H, C> {
+    fn clone(&self) -> Self {
+        self.try_clone().expect("too many producers")
+    }
+}
+
+impl<H, C: Control<H>> Drop for MemeProducer<H, C> {
+    fn drop(&mut self) {
+        self.queue.control.detach_shared(Side::Right);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        panic::{self, AssertUnwindSafe},
+        path::Path,
+        process::Command,
+        sync::mpsc,
+        thread,
+    };
+
+    use super::MemeProducer;
+    use crate::{
+        control::Side,
+        handshake::{named_file, NamedFileHandshakeResult},
+        testing::{dead_pid, open, recv, try_recv, TempPath},
+        Control, Corrupted, ShmemFutexControl, ShmemFutexControlConfig,
+    };
+
+    fn producer(path: &Path) -> MemeProducer<NamedFileHandshakeResult, ShmemFutexControl> {
+        // SAFETY: test files are only used through queues.
+        MemeProducer::new(unsafe { named_file(path, 4096) }.unwrap()).unwrap()
+    }
+
+    #[test]
+    fn messages_are_published_in_reservation_order() {
+        let path = TempPath::new();
+        let producer = producer(&path);
+        let receiver = open(&path, 4096, ShmemFutexControlConfig::default());
+        let (reserved_tx, reserved_rx) = mpsc::channel();
+        let (write_tx, write_rx) = mpsc::channel::<()>();
+
+        thread::scope(|scope| {
+            let slow = producer.clone();
+            let slow = scope.spawn(move || {
+                slow.send_with(4, |dst| {
+                    reserved_tx.send(()).unwrap();
+                    write_rx.recv().unwrap();
+                    dst.copy_from_slice(b"slow");
+                })
+            });
+            reserved_rx.recv().unwrap();
+
+            // Written after the slow one was reserved, so it waits for it to be published.
+            producer.send(b"fast").unwrap();
+            let err = try_recv(&receiver).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+            write_tx.send(()).unwrap();
+            slow.join().unwrap().unwrap();
+        });
+
+        assert_eq!(recv(&receiver).unwrap(), b"slow");
+        assert_eq!(recv(&receiver).unwrap(), b"fast");
+    }
+
+    #[test]
+    fn panicking_writer_does_not_block_others() {
+        let path = TempPath::new();
+        let producer = producer(&path);
+        let receiver = open(&path, 4096, ShmemFutexControlConfig::default());
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| {
+            producer.send_with(100, |_dst| panic!("writer failed"))
+        }));
+        assert!(res.is_err());
+        producer.send(b"after").unwrap();
+
+        if cfg!(feature = "verify") {
+            let err = recv(&receiver).unwrap_err();
+            assert_eq!(Corrupted::from_io(&err).unwrap().got_seq, Some(1));
+        }
+        assert_eq!(recv(&receiver).unwrap(), b"after");
+        let err = try_recv(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    /// Reserves space as if process `pid` did it, and never writes it.
+    fn reserve_for(producer: &MemeProducer<NamedFileHandshakeResult, ShmemFutexControl>, pid: u32) {
+        let control = &producer.queue.control;
+        let _guard = Control::<NamedFileHandshakeResult>::lock(control, Side::Right);
+        let producers = Control::<NamedFileHandshakeResult>::producers(control);
+        let right_offset = Control::<NamedFileHandshakeResult>::load_offset(control, Side::Right);
+        let start = producers.head(right_offset).unwrap();
+        producers.push(start + 100, pid);
+    }
+
+    #[test]
+    fn dead_producers_do_not_block_others() {
+        let path = TempPath::new();
+        let producer = producer(&path);
+        let receiver = open(&path, 4096, ShmemFutexControlConfig::default());
+
+        // Producer died before we published, so we publish its reservation as padding.
+        reserve_for(&producer, dead_pid());
+        producer.send(b"first").unwrap();
+        assert_eq!(recv(&receiver).unwrap(), b"first");
+
+        // Producer dies after we published, so the receiver notices while it waits.
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        reserve_for(&producer, child.id());
+        producer.send(b"second").unwrap();
+        let err = try_recv(&receiver).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(recv(&receiver).unwrap(), b"second");
+    }
+
+    #[test]
+    fn clones_share_the_right_side() {
+        let path = TempPath::new();
+        let producer = producer(&path);
+        let clone = producer.try_clone().unwrap();
+        drop(producer);
+        clone.send(b"still works").unwrap();
+    }
+}
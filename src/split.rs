@@ -0,0 +1,431 @@
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "async")]
+use crate::control::AsyncControl;
+use crate::{
+    control::Side, handshake::HandshakeResult, Control, MemeQueue, MemeWriter, MessageMeta,
+    Messages, RecvGuard, SendBatch, SendGuard,
+};
+
+/// Sending half of a [`MemeQueue`]. Only one sender can exist for a queue at a time.
+///
+/// If the receiver process dies, waiting for space fails with [`io::ErrorKind::BrokenPipe`].
+///
+/// Queue is closed when the sender is dropped, see [`MemeSender::close()`]. Claiming a new sender
+/// reopens it.
+pub struct MemeSender<H, C: Control<H>> {
+    queue: Arc<MemeQueue<H, C>>,
+}
+
+/// Receiving half of a [`MemeQueue`]. Only one receiver can exist for a queue at a time.
+///
+/// If the sender process dies, waiting for messages fails with [`io::ErrorKind::BrokenPipe`] once
+/// everything it sent is received. If the sender closed the queue, it fails with
+/// [`io::ErrorKind::UnexpectedEof`] instead.
+pub struct MemeReceiver<H, C: Control<H>> {
+    queue: Arc<MemeQueue<H, C>>,
+}
+
+impl<H: HandshakeResult, C: Control<H>> MemeQueue<H, C> {
+    /// Splits the queue into sending and receiving halves, claiming both roles for this process.
+    pub fn into_split(self) -> io::Result<(MemeSender<H, C>, MemeReceiver<H, C>)> {
+        let queue = Arc::new(self);
+        let sender = MemeSender::from_queue(Arc::clone(&queue))?;
+        let receiver = MemeReceiver::from_queue(queue)?;
+        Ok((sender, receiver))
+    }
+}
+
+impl<H: HandshakeResult, C: Control<H>> MemeSender<H, C> {
+    pub fn new(handshake_result: H) -> io::Result<Self>
+    where
+        C::Config: Default,
+    {
+        Self::from_queue(Arc::new(MemeQueue::new(handshake_result)?))
+    }
+
+    pub fn with_config(handshake_result: H, config: C::Config) -> io::Result<Self> {
+        Self::from_queue(Arc::new(MemeQueue::with_config(handshake_result, config)?))
+    }
+}
+
+impl<H, C: Control<H>> MemeSender<H, C> {
+    pub(crate) fn from_queue(queue: Arc<MemeQueue<H, C>>) -> io::Result<Self> {
+        queue.control.claim(Side::Right)?;
+        queue.control.set_closed(false);
+        Ok(Self { queue })
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> &crate::stats::Stats {
+        self.queue.stats()
+    }
+
+    /// See [`MemeQueue::send()`].
+    pub fn send<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.send(cb)
+    }
+
+    /// See [`MemeQueue::send_timeout()`].
+    pub fn send_timeout<R, E, F>(&self, timeout: Duration, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.send_timeout(timeout, cb)
+    }
+
+    /// See [`MemeQueue::send_deadline()`].
+    pub fn send_deadline<R, E, F>(&self, deadline: Instant, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.send_deadline(deadline, cb)
+    }
+
+    /// See [`MemeQueue::send_tagged()`].
+    pub fn send_tagged<R, E, F>(&self, tag: u32, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.send_tagged(tag, cb)
+    }
+
+    /// See [`MemeQueue::send_tagged_timeout()`].
+    pub fn send_tagged_timeout<R, E, F>(&self, tag: u32, timeout: Duration, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.send_tagged_timeout(tag, timeout, cb)
+    }
+
+    /// See [`MemeQueue::send_tagged_deadline()`].
+    pub fn send_tagged_deadline<R, E, F>(&self, tag: u32, deadline: Instant, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut MemeWriter<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.send_tagged_deadline(tag, deadline, cb)
+    }
+
+    /// See [`MemeQueue::send_batch()`].
+    pub fn send_batch<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut SendBatch<'_, H, C>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.send_batch(cb)
+    }
+
+    /// See [`MemeQueue::send_reserve()`].
+    pub fn send_reserve(&self, len: usize) -> io::Result<SendGuard<'_, H, C>> {
+        self.queue.send_reserve(len)
+    }
+
+    /// See [`MemeQueue::send_reserve_aligned()`].
+    pub fn send_reserve_aligned(
+        &self,
+        len: usize,
+        align: usize,
+    ) -> io::Result<SendGuard<'_, H, C>> {
+        self.queue.send_reserve_aligned(len, align)
+    }
+
+    /// See [`MemeQueue::send_pod()`].
+    #[cfg(feature = "pod")]
+    pub fn send_pod<T: bytemuck::NoUninit>(&self, value: &T) -> io::Result<()> {
+        self.queue.send_pod(value)
+    }
+
+    /// See [`MemeQueue::send_archived()`].
+    #[cfg(feature = "rkyv")]
+    pub fn send_archived<T>(&self, value: &T) -> io::Result<()>
+    where
+        T: for<'a, 'b> rkyv::Serialize<crate::ArchiveSerializer<'a, 'b>>,
+    {
+        self.queue.send_archived(value)
+    }
+
+    /// See [`MemeQueue::try_send()`].
+    pub fn try_send(&self, buf: &[u8]) -> io::Result<()> {
+        self.queue.try_send(buf)
+    }
+
+    /// See [`MemeQueue::close()`]. Dropping the sender closes the queue too, but this allows to
+    /// handle errors.
+    pub fn close(&self) -> io::Result<()> {
+        self.queue.close()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<H, C: AsyncControl<H>> MemeSender<H, C> {
+    /// See [`MemeQueue::send_async()`].
+    pub async fn send_async(&self, buf: &[u8]) -> io::Result<()> {
+        self.queue.send_async(buf).await
+    }
+
+    /// See [`MemeQueue::sink()`].
+    #[cfg(feature = "futures")]
+    pub fn sink(&self, max_message_size: usize) -> crate::SendSink<&MemeQueue<H, C>, H, C> {
+        self.queue.sink(max_message_size)
+    }
+}
+
+impl<H, C: Control<H>> Drop for MemeSender<H, C> {
+    fn drop(&mut self) {
+        // Can't report errors from drop, use `.close()` to handle them.
+        let _res = self.queue.close();
+        self.queue.control.release(Side::Right);
+    }
+}
+
+impl<H: HandshakeResult, C: Control<H>> MemeReceiver<H, C> {
+    pub fn new(handshake_result: H) -> io::Result<Self>
+    where
+        C::Config: Default,
+    {
+        Self::from_queue(Arc::new(MemeQueue::new(handshake_result)?))
+    }
+
+    pub fn with_config(handshake_result: H, config: C::Config) -> io::Result<Self> {
+        Self::from_queue(Arc::new(MemeQueue::with_config(handshake_result, config)?))
+    }
+}
+
+impl<H, C: Control<H>> MemeReceiver<H, C> {
+    pub(crate) fn from_queue(queue: Arc<MemeQueue<H, C>>) -> io::Result<Self> {
+        queue.control.claim(Side::Left)?;
+        Ok(Self { queue })
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> &crate::stats::Stats {
+        self.queue.stats()
+    }
+
+    /// See [`MemeQueue::recv()`].
+    pub fn recv<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.recv(cb)
+    }
+
+    /// See [`MemeQueue::recv_timeout()`].
+    pub fn recv_timeout<R, E, F>(&self, timeout: Duration, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.recv_timeout(timeout, cb)
+    }
+
+    /// See [`MemeQueue::recv_deadline()`].
+    pub fn recv_deadline<R, E, F>(&self, deadline: Instant, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.recv_deadline(deadline, cb)
+    }
+
+    /// See [`MemeQueue::recv_pod()`].
+    #[cfg(feature = "pod")]
+    pub fn recv_pod<T, R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        T: bytemuck::AnyBitPattern,
+        F: FnOnce(&T) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.recv_pod(cb)
+    }
+
+    /// See [`MemeQueue::recv_archived()`].
+    #[cfg(feature = "rkyv")]
+    pub fn recv_archived<T, R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        T: rkyv::Archive,
+        rkyv::Archived<T>: for<'a> rkyv::bytecheck::CheckBytes<
+            rkyv::api::high::HighValidator<'a, rkyv::rancor::Error>,
+        >,
+        F: FnOnce(&rkyv::Archived<T>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.recv_archived::<T, _, _, _>(cb)
+    }
+
+    /// See [`MemeQueue::recv_batch()`].
+    pub fn recv_batch<R, E, F>(&self, max: usize, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&mut Messages<'_>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.recv_batch(max, cb)
+    }
+
+    /// See [`MemeQueue::recv_guard()`].
+    pub fn recv_guard(&self) -> io::Result<RecvGuard<'_, H, C>> {
+        self.queue.recv_guard()
+    }
+
+    /// See [`MemeQueue::try_recv()`].
+    pub fn try_recv<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.try_recv(cb)
+    }
+
+    /// See [`MemeQueue::recv_meta()`].
+    pub fn recv_meta<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(Option<MessageMeta>, &[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.recv_meta(cb)
+    }
+
+    /// See [`MemeQueue::recv_meta_timeout()`].
+    pub fn recv_meta_timeout<R, E, F>(&self, timeout: Duration, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(Option<MessageMeta>, &[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.recv_meta_timeout(timeout, cb)
+    }
+
+    /// See [`MemeQueue::recv_meta_deadline()`].
+    pub fn recv_meta_deadline<R, E, F>(&self, deadline: Instant, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(Option<MessageMeta>, &[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.recv_meta_deadline(deadline, cb)
+    }
+
+    /// See [`MemeQueue::try_recv_meta()`].
+    pub fn try_recv_meta<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(Option<MessageMeta>, &[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.try_recv_meta(cb)
+    }
+}
+
+#[cfg(feature = "async")]
+impl<H, C: AsyncControl<H>> MemeReceiver<H, C> {
+    /// See [`MemeQueue::recv_async()`].
+    pub async fn recv_async<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.recv_async(cb).await
+    }
+
+    /// See [`MemeQueue::stream()`].
+    #[cfg(feature = "futures")]
+    pub fn stream(&self) -> crate::RecvStream<&MemeQueue<H, C>, H, C> {
+        self.queue.stream()
+    }
+}
+
+impl<H, C: Control<H>> Drop for MemeReceiver<H, C> {
+    fn drop(&mut self) {
+        self.queue.control.release(Side::Left);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write as _},
+        sync::Arc,
+    };
+
+    use super::{MemeReceiver, MemeSender};
+    use crate::{
+        handshake::NamedFileHandshakeResult,
+        testing::{open, pair, TempPath, TestQueue},
+        ShmemFutexControl, ShmemFutexControlConfig,
+    };
+
+    type Sender = MemeSender<NamedFileHandshakeResult, ShmemFutexControl>;
+    type Receiver = MemeReceiver<NamedFileHandshakeResult, ShmemFutexControl>;
+
+    fn claim_sender(queue: TestQueue) -> io::Result<Sender> {
+        MemeSender::from_queue(Arc::new(queue))
+    }
+
+    fn claim_receiver(queue: TestQueue) -> io::Result<Receiver> {
+        MemeReceiver::from_queue(Arc::new(queue))
+    }
+
+    fn recv(receiver: &Receiver) -> io::Result<Vec<u8>> {
+        receiver.recv(|buf| Ok(buf.to_vec()))
+    }
+
+    #[test]
+    fn halves_are_claimed_once() {
+        let path = TempPath::new();
+        let (owner, peer) = pair(&path, 4096);
+        let first_sender = claim_sender(owner).unwrap();
+        let receiver = claim_receiver(peer).unwrap();
+
+        first_sender
+            .send(|writer| writer.write_all(b"first"))
+            .unwrap();
+        assert_eq!(recv(&receiver).unwrap(), b"first");
+
+        let another = || open(&path, 4096, ShmemFutexControlConfig::default());
+        let Err(err) = claim_sender(another()) else {
+            panic!("claimed a second sender");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        let Err(err) = claim_receiver(another()) else {
+            panic!("claimed a second receiver");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+
+        // Dropped sender closes the queue, and the next one reopens it.
+        drop(first_sender);
+        assert_eq!(
+            recv(&receiver).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        let second_sender = claim_sender(another()).unwrap();
+        second_sender
+            .send(|writer| writer.write_all(b"second"))
+            .unwrap();
+        assert_eq!(recv(&receiver).unwrap(), b"second");
+    }
+
+    #[test]
+    fn split_queue_talks_to_itself() {
+        let path = TempPath::new();
+        let queue = open(&path, 4096, ShmemFutexControlConfig::default());
+        let (sender, receiver) = queue.into_split().unwrap();
+
+        for idx in 0..1000_u32 {
+            sender
+                .send(|writer| writer.write_all(&idx.to_le_bytes()))
+                .unwrap();
+            assert_eq!(recv(&receiver).unwrap(), idx.to_le_bytes());
+        }
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    io,
+    ops::Deref,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_core::Stream;
+use futures_sink::Sink;
+
+use crate::{
+    control::{AsyncControl, Side, WaitState},
+    MemeQueue,
+};
+
+/// A [`Stream`] of received messages. Ends once the sender closed the queue and every message is
+/// received.
+///
+/// `Q` is anything that derefs to a [`MemeQueue`], e.g. `&MemeQueue` or `Arc<MemeQueue>`.
+pub struct RecvStream<Q, H, C>
+where
+    Q: Deref<Target = MemeQueue<H, C>>,
+    C: AsyncControl<H>,
+{
+    queue: Q,
+    wait_state: WaitState,
+}
+
+impl<Q, H, C> RecvStream<Q, H, C>
+where
+    Q: Deref<Target = MemeQueue<H, C>>,
+    C: AsyncControl<H>,
+{
+    pub fn new(queue: Q) -> Self {
+        Self {
+            queue,
+            wait_state: WaitState::new(),
+        }
+    }
+}
+
+// We never pin anything inside.
+impl<Q, H, C> Unpin for RecvStream<Q, H, C>
+where
+    Q: Deref<Target = MemeQueue<H, C>>,
+    C: AsyncControl<H>,
+{
+}
+
+impl<Q, H, C> Stream for RecvStream<Q, H, C>
+where
+    Q: Deref<Target = MemeQueue<H, C>>,
+    C: AsyncControl<H>,
+{
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match this
+                .queue
+                .recv_now(|_meta, buf| io::Result::Ok(buf.to_vec()))
+            {
+                Ok(res) => {
+                    this.queue
+                        .control
+                        .cancel_wait(Side::Right, &mut this.wait_state);
+                    return Poll::Ready(Some(res));
+                }
+                Err((_cb, right_offset)) => {
+                    match this.queue.check_eof(right_offset) {
+                        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                            this.queue
+                                .control
+                                .cancel_wait(Side::Right, &mut this.wait_state);
+                            return Poll::Ready(None);
+                        }
+                        res => res?,
+                    }
+                    ready!(this.queue.control.poll_wait(
+                        Side::Right,
+                        right_offset,
+                        &mut this.wait_state,
+                        cx
+                    ))?;
+                    this.queue.publish_abandoned(right_offset)?;
+                }
+            }
+        }
+    }
+}
+
+impl<Q, H, C> Drop for RecvStream<Q, H, C>
+where
+    Q: Deref<Target = MemeQueue<H, C>>,
+    C: AsyncControl<H>,
+{
+    fn drop(&mut self) {
+        self.queue
+            .control
+            .cancel_wait(Side::Right, &mut self.wait_state);
+    }
+}
+
+/// A [`Sink`] of messages of up to `max_message_size` bytes.
+///
+/// [`Sink::poll_ready()`] waits until a message of `max_message_size` bytes fits into the queue,
+/// and [`Sink::start_send()`] writes the message straight into it, so nothing is buffered and
+/// flushing does nothing. Receivers never take space back, but other senders can, so the sink
+/// must be the only sender while it's used. Otherwise, `start_send()` can fail with
+/// [`io::ErrorKind::WouldBlock`].
+///
+/// Closing the sink closes the queue, see [`MemeQueue::close()`]. Sending after that fails with
+/// [`io::ErrorKind::BrokenPipe`].
+pub struct SendSink<Q, H, C>
+where
+    Q: Deref<Target = MemeQueue<H, C>>,
+    C: AsyncControl<H>,
+{
+    queue: Q,
+    max_message_size: usize,
+    // Whether `poll_ready()` found enough space since the last `start_send()`.
+    ready: bool,
+    closed: bool,
+    wait_state: WaitState,
+}
+
+impl<Q, H, C> SendSink<Q, H, C>
+where
+    Q: Deref<Target = MemeQueue<H, C>>,
+    C: AsyncControl<H>,
+{
+    pub fn new(queue: Q, max_message_size: usize) -> Self {
+        Self {
+            queue,
+            max_message_size,
+            ready: false,
+            closed: false,
+            wait_state: WaitState::new(),
+        }
+    }
+
+    fn poll_reserve(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.check_open()?;
+        if self.ready {
+            return Poll::Ready(Ok(()));
+        }
+
+        let frame_size = self.queue.frame_size(self.max_message_size)?;
+        loop {
+            // Right lock blocks the thread, so it's never held while we wait.
+            let res = {
+                let _guard = self.queue.control.lock(Side::Right);
+                let mut right_offset = self.queue.control.load_offset(Side::Right);
+                self.queue.try_reserve(&mut right_offset, frame_size)
+            };
+            match res {
+                Ok(()) => break,
+                Err(left_offset) => {
+                    ready!(self.queue.control.poll_wait(
+                        Side::Left,
+                        left_offset,
+                        &mut self.wait_state,
+                        cx
+                    ))?;
+                }
+            }
+        }
+
+        self.queue
+            .control
+            .cancel_wait(Side::Left, &mut self.wait_state);
+        self.ready = true;
+        Poll::Ready(Ok(()))
+    }
+
+    fn check_open(&self) -> io::Result<()> {
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "sink is closed"));
+        }
+        Ok(())
+    }
+}
+
+// We never pin anything inside.
+impl<Q, H, C> Unpin for SendSink<Q, H, C>
+where
+    Q: Deref<Target = MemeQueue<H, C>>,
+    C: AsyncControl<H>,
+{
+}
+
+impl<Q, H, C> Sink<&[u8]> for SendSink<Q, H, C>
+where
+    Q: Deref<Target = MemeQueue<H, C>>,
+    C: AsyncControl<H>,
+{
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_reserve(cx)
+    }
+
+    /// Fails with [`io::ErrorKind::InvalidInput`] if `item` is larger than `max_message_size`.
+    fn start_send(self: Pin<&mut Self>, item: &[u8]) -> io::Result<()> {
+        let this = self.get_mut();
+        this.check_open()?;
+        debug_assert!(this.ready, "`start_send()` called without `poll_ready()`");
+        this.ready = false;
+
+        if item.len() > this.max_message_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message is larger than `max_message_size` of the sink",
+            ));
+        }
+        if this.queue.send_now(item)?.is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "another sender took the space found by `poll_ready()`",
+            ));
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closed {
+            this.queue.close()?;
+            this.closed = true;
+            this.ready = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<Q, H, C> Drop for SendSink<Q, H, C>
+where
+    Q: Deref<Target = MemeQueue<H, C>>,
+    C: AsyncControl<H>,
+{
+    fn drop(&mut self) {
+        self.queue
+            .control
+            .cancel_wait(Side::Left, &mut self.wait_state);
+    }
+}
+
+impl<H, C: AsyncControl<H>> MemeQueue<H, C> {
+    /// Returns a [`Stream`] of received messages borrowing this queue.
+    pub fn stream(&self) -> RecvStream<&Self, H, C> {
+        RecvStream::new(self)
+    }
+
+    /// Returns a [`Sink`] of messages of up to `max_message_size` bytes borrowing this queue.
+    pub fn sink(&self, max_message_size: usize) -> SendSink<&Self, H, C> {
+        SendSink::new(self, max_message_size)
+    }
+}
+
+#[cfg(all(test, feature = "handshake_uds_memfd"))]
+mod tests {
+    use std::io;
+
+    use futures_util::{stream, SinkExt as _, StreamExt as _};
+
+    use crate::testing::{eventfd_pair, TempPath};
+
+    #[tokio::test]
+    async fn sink_feeds_stream() {
+        let path = TempPath::new();
+        let (sender, receiver) = eventfd_pair(&path, 4096);
+        let mut sink = sender.sink(8);
+        let mut stream = receiver.stream();
+
+        for idx in 0..2000_u64 {
+            sink.send(&idx.to_le_bytes()).await.unwrap();
+            let message = stream.next().await.unwrap().unwrap();
+            assert_eq!(message, idx.to_le_bytes());
+        }
+        let err = sink.send(&[0; 9]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn closing_sink_ends_stream() {
+        let path = TempPath::new();
+        let (sender, receiver) = eventfd_pair(&path, 4096);
+        // Many times the ring, so both sides have to wait for each other.
+        let messages: Vec<Vec<u8>> = (0..2000_u32)
+            .map(|idx| idx.to_le_bytes().repeat(idx as usize % 50))
+            .collect();
+
+        let send = async {
+            let mut sink = sender.sink(200);
+            let mut items = stream::iter(messages.iter().map(|message| Ok(message.as_slice())));
+            sink.send_all(&mut items).await.unwrap();
+            sink.close().await.unwrap();
+            let err = sink.send(b"late").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        };
+        let recv = receiver.stream().map(Result::unwrap).collect::<Vec<_>>();
+        let ((), received) = tokio::join!(send, recv);
+        assert_eq!(received, messages);
+    }
+}
@@ -0,0 +1,113 @@
+//! Helpers shared by unit tests.
+
+use std::{
+    env, fs,
+    io::{self, Write as _},
+    ops::Deref,
+    path::{Path, PathBuf},
+    process::{self, Command},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+#[cfg(feature = "handshake_uds_memfd")]
+use std::{thread, time::Duration};
+
+#[cfg(feature = "handshake_uds_memfd")]
+use crate::{
+    handshake::{uds_memfd, uds_memfd_connect, HandshakeError, UdsMemfdHandshakeResult},
+    EventFdControl,
+};
+
+use crate::{
+    handshake::{named_file, NamedFileHandshakeResult},
+    MemeQueue, ShmemFutexControl, ShmemFutexControlConfig,
+};
+
+pub(crate) type TestQueue = MemeQueue<NamedFileHandshakeResult, ShmemFutexControl>;
+
+/// Path of a file that's unique to a test, removed on drop.
+pub(crate) struct TempPath(PathBuf);
+
+impl TempPath {
+    pub(crate) fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+        Self(env::temp_dir().join(format!("memequeue-test-{}-{id}", process::id())))
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _res = fs::remove_file(&self.0);
+    }
+}
+
+/// Opens a queue in a named file at `path`. The first one opened becomes the owner.
+pub(crate) fn open(path: &Path, queue_size: usize, config: ShmemFutexControlConfig) -> TestQueue {
+    // SAFETY: test files are only used through queues.
+    let handshake_result = unsafe { named_file(path, queue_size) }.unwrap();
+    MemeQueue::with_config(handshake_result, config).unwrap()
+}
+
+/// Opens both ends of a new queue, the owner comes first.
+pub(crate) fn pair(path: &Path, queue_size: usize) -> (TestQueue, TestQueue) {
+    let owner = open(path, queue_size, ShmemFutexControlConfig::default());
+    let peer = open(path, queue_size, ShmemFutexControlConfig::default());
+    (owner, peer)
+}
+
+#[cfg(feature = "handshake_uds_memfd")]
+pub(crate) type EventFdQueue = MemeQueue<UdsMemfdHandshakeResult, EventFdControl>;
+
+/// Like [`pair()`], but over a unix socket at `path`, so eventfds can be exchanged.
+#[cfg(feature = "handshake_uds_memfd")]
+pub(crate) fn eventfd_pair(path: &Path, queue_size: usize) -> (EventFdQueue, EventFdQueue) {
+    let owner = {
+        let path = path.to_owned();
+        thread::spawn(move || MemeQueue::new(uds_memfd(path, queue_size).unwrap()).unwrap())
+    };
+    let handshake_result = loop {
+        match uds_memfd_connect(path) {
+            Ok(handshake_result) => break handshake_result,
+            // Owner isn't listening yet.
+            Err(HandshakeError::Io(err))
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+                ) =>
+            {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(err) => panic!("failed to connect: {err}"),
+        }
+    };
+    let peer = MemeQueue::new(handshake_result).unwrap();
+    (owner.join().unwrap(), peer)
+}
+
+pub(crate) fn send(queue: &TestQueue, buf: &[u8]) -> io::Result<()> {
+    queue.send(|writer| writer.write_all(buf))
+}
+
+pub(crate) fn recv(queue: &TestQueue) -> io::Result<Vec<u8>> {
+    queue.recv(|buf| Ok(buf.to_vec()))
+}
+
+pub(crate) fn try_recv(queue: &TestQueue) -> io::Result<Vec<u8>> {
+    queue.try_recv(|buf| Ok(buf.to_vec()))
+}
+
+/// Pid of a process that already exited.
+pub(crate) fn dead_pid() -> u32 {
+    let mut child = Command::new("true").spawn().unwrap();
+    let pid = child.id();
+    child.wait().unwrap();
+    pid
+}
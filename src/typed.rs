@@ -0,0 +1,241 @@
+use std::{
+    io,
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{handshake::HandshakeResult, Control, MemeQueue};
+
+/// Serialization format of a [`TypedMemeQueue`].
+pub trait Codec {
+    /// Serializes `value` into `writer`. Any error means that the message must not be sent.
+    fn encode<T: Serialize + ?Sized, W: io::Write>(value: &T, writer: W) -> io::Result<()>;
+    fn decode<'a, T: Deserialize<'a>>(buf: &'a [u8]) -> io::Result<T>;
+}
+
+/// [`postcard`] codec.
+#[cfg(feature = "postcard")]
+pub struct Postcard;
+
+#[cfg(feature = "postcard")]
+impl Codec for Postcard {
+    fn encode<T: Serialize + ?Sized, W: io::Write>(value: &T, writer: W) -> io::Result<()> {
+        postcard::to_io(value, writer).map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    fn decode<'a, T: Deserialize<'a>>(buf: &'a [u8]) -> io::Result<T> {
+        postcard::from_bytes(buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// [`bincode`] codec with default options.
+#[cfg(feature = "bincode")]
+pub struct Bincode;
+
+#[cfg(feature = "bincode")]
+impl Codec for Bincode {
+    fn encode<T: Serialize + ?Sized, W: io::Write>(value: &T, writer: W) -> io::Result<()> {
+        bincode::serialize_into(writer, value).map_err(|err| match *err {
+            bincode::ErrorKind::Io(err) => err,
+            err => io::Error::other(err),
+        })
+    }
+
+    fn decode<'a, T: Deserialize<'a>>(buf: &'a [u8]) -> io::Result<T> {
+        bincode::deserialize(buf).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// A [`MemeQueue`] of `T` values serialized with `D`.
+///
+/// Values are serialized straight into the queue through [`MemeWriter`](crate::MemeWriter).
+pub struct TypedMemeQueue<T: ?Sized, D, H, C> {
+    queue: MemeQueue<H, C>,
+    _value: PhantomData<fn(&T)>,
+    _codec: PhantomData<fn() -> D>,
+}
+
+impl<T: ?Sized, D, H: HandshakeResult, C: Control<H>> TypedMemeQueue<T, D, H, C> {
+    pub fn new(handshake_result: H) -> io::Result<Self>
+    where
+        C::Config: Default,
+    {
+        Ok(Self::from_queue(MemeQueue::new(handshake_result)?))
+    }
+
+    pub fn with_config(handshake_result: H, config: C::Config) -> io::Result<Self> {
+        Ok(Self::from_queue(MemeQueue::with_config(
+            handshake_result,
+            config,
+        )?))
+    }
+}
+
+impl<T: ?Sized, D, H, C> TypedMemeQueue<T, D, H, C> {
+    pub fn from_queue(queue: MemeQueue<H, C>) -> Self {
+        Self {
+            queue,
+            _value: PhantomData,
+            _codec: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> MemeQueue<H, C> {
+        self.queue
+    }
+}
+
+impl<T, D, H, C> TypedMemeQueue<T, D, H, C>
+where
+    T: Serialize + ?Sized,
+    D: Codec,
+    C: Control<H>,
+{
+    /// Serializes `value` directly into the queue. If serialization fails, nothing is sent.
+    pub fn send(&self, value: &T) -> io::Result<()> {
+        self.queue.send(|writer| D::encode(value, writer))
+    }
+
+    /// Like [`TypedMemeQueue::send()`], but fails with [`io::ErrorKind::TimedOut`] if there's
+    /// still no space after `timeout`.
+    pub fn send_timeout(&self, value: &T, timeout: Duration) -> io::Result<()> {
+        self.queue
+            .send_timeout(timeout, |writer| D::encode(value, writer))
+    }
+
+    /// Like [`TypedMemeQueue::send()`], but fails with [`io::ErrorKind::TimedOut`] if there's
+    /// still no space by `deadline`.
+    pub fn send_deadline(&self, value: &T, deadline: Instant) -> io::Result<()> {
+        self.queue
+            .send_deadline(deadline, |writer| D::encode(value, writer))
+    }
+}
+
+impl<T, D, H, C> TypedMemeQueue<T, D, H, C>
+where
+    T: DeserializeOwned,
+    D: Codec,
+    C: Control<H>,
+{
+    /// Receives and deserializes a value. Message is consumed even if it fails to deserialize.
+    pub fn recv(&self) -> io::Result<T> {
+        self.queue.recv(|buf| D::decode(buf))
+    }
+
+    /// Like [`TypedMemeQueue::recv()`], but fails with [`io::ErrorKind::TimedOut`] if there's no
+    /// message after `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> io::Result<T> {
+        self.queue.recv_timeout(timeout, |buf| D::decode(buf))
+    }
+
+    /// Like [`TypedMemeQueue::recv()`], but fails with [`io::ErrorKind::TimedOut`] if there's no
+    /// message by `deadline`.
+    pub fn recv_deadline(&self, deadline: Instant) -> io::Result<T> {
+        self.queue.recv_deadline(deadline, |buf| D::decode(buf))
+    }
+
+    /// Like [`TypedMemeQueue::recv()`], but fails with [`io::ErrorKind::WouldBlock`] instead of
+    /// waiting if the queue is empty.
+    pub fn try_recv(&self) -> io::Result<T> {
+        self.queue.try_recv(|buf| D::decode(buf))
+    }
+}
+
+impl<T: ?Sized, D: Codec, H, C: Control<H>> TypedMemeQueue<T, D, H, C> {
+    /// Receives a message without copying it out of the queue. Callback can deserialize it into
+    /// any type borrowing from shared memory with [`Encoded::decode()`].
+    pub fn recv_with<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: for<'a> FnOnce(Encoded<'a, D>) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        self.queue.recv(|buf| {
+            cb(Encoded {
+                buf,
+                _codec: PhantomData,
+            })
+        })
+    }
+}
+
+/// A serialized message that's still in the queue, see [`TypedMemeQueue::recv_with()`].
+pub struct Encoded<'a, D> {
+    buf: &'a [u8],
+    _codec: PhantomData<fn() -> D>,
+}
+
+impl<'a, D: Codec> Encoded<'a, D> {
+    pub fn decode<T: Deserialize<'a>>(&self) -> io::Result<T> {
+        D::decode(self.buf)
+    }
+
+    pub fn bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        time::{Duration, Instant},
+    };
+
+    use super::{Codec, TypedMemeQueue};
+    use crate::{
+        handshake::NamedFileHandshakeResult,
+        testing::{pair, send, TempPath},
+        ShmemFutexControl,
+    };
+
+    type Message = (u32, String, Vec<u16>);
+    type Typed<D> = TypedMemeQueue<Message, D, NamedFileHandshakeResult, ShmemFutexControl>;
+
+    fn round_trip<D: Codec>() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+        let (sender, receiver) = (
+            Typed::<D>::from_queue(sender),
+            Typed::<D>::from_queue(receiver),
+        );
+
+        // Many times the ring, so values wrap around.
+        for id in 0..500_u32 {
+            let message = (id, format!("message {id}"), (0..id as u16 % 50).collect());
+            sender.send(&message).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            assert_eq!(receiver.recv_deadline(deadline).unwrap(), message);
+        }
+
+        sender.send(&(7, "borrowed".to_owned(), vec![])).unwrap();
+        receiver
+            .recv_with(|encoded| {
+                let (id, name, _values): (u32, &str, Vec<u16>) = encoded.decode()?;
+                assert_eq!((id, name), (7, "borrowed"));
+                io::Result::Ok(())
+            })
+            .unwrap();
+
+        let err = receiver.recv_deadline(Instant::now()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        // Truncated value.
+        send(&sender.into_inner(), &[1]).unwrap();
+        let err = receiver.try_recv().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[cfg(feature = "postcard")]
+    #[test]
+    fn postcard_round_trip() {
+        round_trip::<super::Postcard>();
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn bincode_round_trip() {
+        round_trip::<super::Bincode>();
+    }
+}
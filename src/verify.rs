@@ -0,0 +1,196 @@
+use std::{error::Error, fmt, io};
+
+use crate::{Frame, MessageMeta, Offset};
+
+/// A frame that failed verification on receive. Surfaced as an [`io::Error`] of kind
+/// [`io::ErrorKind::InvalidData`], use [`Corrupted::from_io()`] to get it back.
+///
+/// Sequence numbers are only checked for messages with [`MessageMeta`]. With `verify` feature,
+/// every message is sent with it and with a checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Corrupted {
+    /// Sequence number of the message that the receiver expected next.
+    pub expected_seq: u64,
+    /// Sequence number found in the frame, or `None` if the frame header itself is broken.
+    pub got_seq: Option<u64>,
+    pub(crate) reason: Reason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reason {
+    /// Frame claims to extend past the committed part of the queue. Nothing after it can be
+    /// received.
+    Header,
+    /// Messages were lost. Receiver expects `got_seq` next, so the message is still delivered.
+    Gap,
+    /// Message doesn't match its checksum. It's consumed without being delivered.
+    Checksum,
+}
+
+impl Corrupted {
+    pub(crate) fn header(expected_seq: u64) -> Self {
+        Self {
+            expected_seq,
+            got_seq: None,
+            reason: Reason::Header,
+        }
+    }
+
+    /// Returns the [`Corrupted`] error that `err` wraps, if any.
+    pub fn from_io(err: &io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+
+    /// Where receivers continue after `frame` at `offset` failed verification with this error:
+    /// offset of the next frame to read and the sequence number to expect.
+    pub(crate) fn resume(&self, offset: Offset, frame: &Frame<'_>) -> (Offset, u64) {
+        match (self.reason, self.got_seq) {
+            (Reason::Checksum, Some(seq)) => (frame.end, seq + 1),
+            (Reason::Gap, Some(seq)) => (offset, seq),
+            _ => (offset, self.expected_seq),
+        }
+    }
+
+    /// Whether the frame is still in the queue, so the queue can't be received from anymore.
+    pub fn is_fatal(&self) -> bool {
+        self.reason == Reason::Header
+    }
+}
+
+impl fmt::Display for Corrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let expected = self.expected_seq;
+        match (self.reason, self.got_seq) {
+            (Reason::Gap, Some(got)) => {
+                write!(f, "expected message #{expected}, but got #{got}")
+            }
+            (Reason::Checksum, Some(got)) => write!(f, "message #{got} has a wrong checksum"),
+            _ => write!(f, "corrupted frame header before message #{expected}"),
+        }
+    }
+}
+
+impl Error for Corrupted {}
+
+impl From<Corrupted> for io::Error {
+    fn from(corrupted: Corrupted) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, corrupted)
+    }
+}
+
+/// Checksum of a message, stored in [`MessageMeta`] block of frames sent with `verify` feature.
+pub(crate) fn checksum(message: &[u8]) -> u32 {
+    crc32fast::hash(message)
+}
+
+impl Frame<'_> {
+    /// Checks sequence number and checksum of the frame against the next `expected_seq`.
+    pub(crate) fn verify(&self, expected_seq: u64) -> Result<(), Corrupted> {
+        let Some(MessageMeta { seq, .. }) = self.meta else {
+            return Ok(());
+        };
+        let reason = if seq != expected_seq {
+            Reason::Gap
+        } else if self
+            .checksum
+            .is_some_and(|sum| sum != checksum(self.message))
+        {
+            Reason::Checksum
+        } else {
+            return Ok(());
+        };
+        Err(Corrupted {
+            expected_seq,
+            got_seq: Some(seq),
+            reason,
+        })
+    }
+
+    /// Sequence number that receivers expect after this frame is consumed, if it has one.
+    pub(crate) fn next_seq(&self) -> Option<u64> {
+        self.meta.map(|meta| meta.seq + 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write as _},
+        sync::atomic::Ordering,
+    };
+
+    use super::{Corrupted, Reason};
+    use crate::{
+        handshake::NamedFileHandshakeResult,
+        testing::{pair, recv, send, TempPath, TestQueue},
+        write_frame_header, Control,
+    };
+
+    fn corrupted(res: io::Result<Vec<u8>>) -> Corrupted {
+        *Corrupted::from_io(&res.unwrap_err()).unwrap()
+    }
+
+    fn send_tagged(queue: &TestQueue, buf: &[u8]) {
+        queue
+            .send_tagged(0, |writer| writer.write_all(buf))
+            .unwrap();
+    }
+
+    #[cfg(feature = "verify")]
+    #[test]
+    fn checksum_mismatch_skips_message() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        send(&sender, b"hello").unwrap();
+        send(&sender, b"world").unwrap();
+        // SAFETY: the first message starts right after its frame header and metadata.
+        unsafe { *receiver.left.as_ptr().add(crate::MESSAGE_OFFSET) ^= 1 };
+
+        let err = corrupted(recv(&receiver));
+        assert_eq!(err.reason, Reason::Checksum);
+        assert_eq!(err.got_seq, Some(0));
+        assert!(!err.is_fatal());
+        assert_eq!(recv(&receiver).unwrap(), b"world");
+    }
+
+    #[test]
+    fn sequence_gap_is_reported_before_message() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        send_tagged(&sender, b"first");
+        assert_eq!(recv(&receiver).unwrap(), b"first");
+        // As if the next message got lost.
+        Control::<NamedFileHandshakeResult>::next_seq(&sender.control)
+            .fetch_add(1, Ordering::Relaxed);
+        send_tagged(&sender, b"third");
+
+        let err = corrupted(recv(&receiver));
+        assert_eq!(err.reason, Reason::Gap);
+        assert_eq!((err.expected_seq, err.got_seq), (1, Some(2)));
+        assert!(!err.is_fatal());
+        let (meta, message) = receiver
+            .recv_meta(|meta, buf| io::Result::Ok((meta, buf.to_vec())))
+            .unwrap();
+        assert_eq!(meta.unwrap().seq, 2);
+        assert_eq!(message, b"third");
+    }
+
+    #[test]
+    fn broken_frame_header_is_fatal() {
+        let path = TempPath::new();
+        let (sender, receiver) = pair(&path, 4096);
+
+        send(&sender, b"hello").unwrap();
+        // SAFETY: the frame is committed, and nobody reads it concurrently.
+        unsafe { write_frame_header(&receiver.left, 0, 1000) };
+
+        for _ in 0..2 {
+            let err = corrupted(recv(&receiver));
+            assert_eq!(err.reason, Reason::Header);
+            assert_eq!(err.got_seq, None);
+            assert!(err.is_fatal());
+        }
+    }
+}
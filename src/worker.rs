@@ -0,0 +1,172 @@
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{control::Side, handshake::HandshakeResult, Control, MemeQueue, RecvGuard};
+
+/// One of possibly many competing receivers of a [`MemeQueue`]. Every message is delivered to
+/// exactly one worker.
+///
+/// Workers serialize on the left lock, which is held while the callback runs. Copy the message
+/// out before doing anything heavy with it, or other workers will wait for you.
+///
+/// Workers can't be mixed with a [`MemeReceiver`](crate::MemeReceiver) on the same queue.
+/// Multiple worker processes require a handshake that allows more than two peers, such as
+/// [`named_file()`](crate::handshake::named_file) or `uds_memfd_listener()`.
+pub struct MemeWorker<H, C: Control<H>> {
+    queue: Arc<MemeQueue<H, C>>,
+}
+
+impl<H: HandshakeResult, C: Control<H>> MemeWorker<H, C> {
+    pub fn new(handshake_result: H) -> io::Result<Self>
+    where
+        C::Config: Default,
+    {
+        Self::from_queue(Arc::new(MemeQueue::new(handshake_result)?))
+    }
+
+    pub fn with_config(handshake_result: H, config: C::Config) -> io::Result<Self> {
+        Self::from_queue(Arc::new(MemeQueue::with_config(handshake_result, config)?))
+    }
+}
+
+impl<H, C: Control<H>> MemeWorker<H, C> {
+    pub(crate) fn from_queue(queue: Arc<MemeQueue<H, C>>) -> io::Result<Self> {
+        queue.control.attach_shared(Side::Left)?;
+        Ok(Self { queue })
+    }
+
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> &crate::stats::Stats {
+        self.queue.stats()
+    }
+
+    /// See [`MemeQueue::recv()`].
+    pub fn recv<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        let res = self.queue.recv(cb);
+        self.wake_next()?;
+        res
+    }
+
+    /// See [`MemeQueue::recv_timeout()`].
+    pub fn recv_timeout<R, E, F>(&self, timeout: Duration, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        let res = self.queue.recv_timeout(timeout, cb);
+        self.wake_next()?;
+        res
+    }
+
+    /// See [`MemeQueue::recv_deadline()`].
+    pub fn recv_deadline<R, E, F>(&self, deadline: Instant, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        let res = self.queue.recv_deadline(deadline, cb);
+        self.wake_next()?;
+        res
+    }
+
+    /// See [`MemeQueue::try_recv()`].
+    pub fn try_recv<R, E, F>(&self, cb: F) -> Result<R, E>
+    where
+        F: FnOnce(&[u8]) -> Result<R, E>,
+        E: From<io::Error>,
+    {
+        let res = self.queue.try_recv(cb);
+        self.wake_next()?;
+        res
+    }
+
+    /// See [`MemeQueue::recv_guard()`]. Other workers are blocked until the guard is dropped.
+    pub fn recv_guard(&self) -> io::Result<RecvGuard<'_, H, C>> {
+        self.queue.recv_guard()
+    }
+
+    /// Sender only wakes one waiter per notification, and it might send many messages with one
+    /// notification (e.g. with [`MemeQueue::send_batch()`]). So if there's something left after
+    /// we took our message, we pass the notification on to the next sleeping worker. Same goes
+    /// for the notification that the queue is closed.
+    fn wake_next(&self) -> io::Result<()> {
+        let control = &self.queue.control;
+        if control.load_offset(Side::Right) != control.load_offset(Side::Left)
+            || control.is_closed()
+        {
+            control.notify(Side::Right)?;
+        }
+        Ok(())
+    }
+}
+
+impl<H, C: Control<H>> Drop for MemeWorker<H, C> {
+    fn drop(&mut self) {
+        self.queue.control.detach_shared(Side::Left);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::Arc, thread};
+
+    use super::MemeWorker;
+    use crate::{
+        testing::{open, send, TempPath},
+        MemeReceiver, ShmemFutexControlConfig,
+    };
+
+    #[test]
+    fn every_message_goes_to_one_worker() {
+        let path = TempPath::new();
+        let sender = open(&path, 4096, ShmemFutexControlConfig::default());
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let queue = open(&path, 4096, ShmemFutexControlConfig::default());
+                MemeWorker::from_queue(Arc::new(queue)).unwrap()
+            })
+            .collect();
+        let queue = open(&path, 4096, ShmemFutexControlConfig::default());
+        let Err(err) = MemeReceiver::from_queue(Arc::new(queue)) else {
+            panic!("claimed a receiver next to workers");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+
+        let threads: Vec<_> = workers
+            .into_iter()
+            .map(|worker| {
+                thread::spawn(move || {
+                    let mut received = Vec::new();
+                    loop {
+                        match worker.recv(|buf| io::Result::Ok(buf.try_into().unwrap())) {
+                            Ok(id) => received.push(u32::from_le_bytes(id)),
+                            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                                return received
+                            }
+                            Err(err) => panic!("failed to receive: {err}"),
+                        }
+                    }
+                })
+            })
+            .collect();
+        // Many times the ring, so workers have to wait for the sender and vice versa.
+        for id in 0..10_000_u32 {
+            send(&sender, &id.to_le_bytes()).unwrap();
+        }
+        sender.close().unwrap();
+
+        let mut received: Vec<u32> = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect();
+        received.sort_unstable();
+        assert_eq!(received, (0..10_000).collect::<Vec<_>>());
+    }
+}